@@ -0,0 +1,207 @@
+// FPC BM-Lite fingerprint sensor over SPI
+//
+// Framing lives in `lib_hcp`, this driver only moves link frames and
+// waits for the IRQ line.
+
+use crate::hal_main as hal;
+use crate::device::{Spim, SPI_BUF_LEN, Arg, BmLiteError, Command, Packet, PacketWriter, TransportHeader,
+    link_decode, link_encode, link_payload_len, HCP_ACK, HCP_CHUNK, HCP_COMMAND_LEN, LINK_CRC, LINK_HEADER,
+    TRANSPORT_HEADER};
+use hal::gpio::{Pin, Input, Output, PullDown, PushPull};
+use hal::spim;
+use embedded_hal::digital::v2::{InputPin, OutputPin};
+
+// 64 MHz core
+const CYCLES_PER_MS: u32 = 64_000;
+const RESET_PULSE_MS: u32 = 10;
+const RESET_BOOT_MS: u32 = 100;
+
+
+/// Control lines of the sensor, IRQ goes high when the sensor has data
+pub struct BmLitePins {
+    pub cs: Pin<Output<PushPull>>,
+    pub rst: Pin<Output<PushPull>>,
+    pub irq: Pin<Input<PullDown>>,
+}
+
+
+pub struct BmLite<T: spim::Instance> {
+    spim: Spim<T>,
+    pins: BmLitePins,
+    tx_buffor: u32,
+    rx_buffor: u32,
+    timeout_ms: u32,
+}
+
+impl<T> BmLite<T>
+where
+    T: spim::Instance,
+{
+    /// Buffers are RAM addresses of `SPI_BUF_LEN` bytes (`SPI_TX_BUF_DEF`, `SPI_RX_BUF_DEF`)
+    pub fn new(spim: Spim<T>, mut pins: BmLitePins, tx_buffor: u32, rx_buffor: u32) -> Self {
+        pins.cs.set_high().ok();
+        BmLite { spim, pins, tx_buffor, rx_buffor, timeout_ms: 5_000 }
+    }
+
+    pub fn free(self) -> (Spim<T>, BmLitePins) {
+        (self.spim, self.pins)
+    }
+
+    /// IRQ line, e.g. for a GPIOTE channel
+    pub fn irq_pin(&self) -> &Pin<Input<PullDown>> {
+        &self.pins.irq
+    }
+
+    pub fn is_irq(&self) -> bool {
+        self.pins.irq.is_high().unwrap_or(false)
+    }
+
+    /// Longest wait for the sensor to answer
+    pub fn set_timeout(&mut self, timeout_ms: u32) {
+        self.timeout_ms = timeout_ms;
+    }
+
+    /// Hardware reset, blocks until the sensor booted
+    pub fn reset(&mut self) {
+        self.pins.rst.set_low().ok();
+        cortex_m::asm::delay(RESET_PULSE_MS * CYCLES_PER_MS);
+        self.pins.rst.set_high().ok();
+        cortex_m::asm::delay(RESET_BOOT_MS * CYCLES_PER_MS);
+    }
+
+    /// Build packet from `command` and `args`, send it and wait for the answer
+    pub fn call<'r>(&mut self, command: Command, args: &[(Arg, &[u8])], reply: &'r mut [u8])
+        -> Result<Packet<'r>, BmLiteError>
+    {
+        self.start(command, args)?;
+        self.finish(reply)
+    }
+
+    /// Send command without waiting for the answer, sensor raises IRQ
+    /// once it's ready for `finish`
+    pub fn start(&mut self, command: Command, args: &[(Arg, &[u8])]) -> Result<(), BmLiteError> {
+        let mut buffer = [0u8; HCP_COMMAND_LEN];
+        let mut writer = PacketWriter::new(&mut buffer, command)?;
+        for (id, data) in args {
+            writer.arg(*id, data)?;
+        }
+        let packet = writer.finish();
+        self.send(packet)
+    }
+
+    /// Send encoded packet and receive the answer into `reply`
+    pub fn command<'r>(&mut self, packet: &[u8], reply: &'r mut [u8])
+        -> Result<Packet<'r>, BmLiteError>
+    {
+        self.send(packet)?;
+        self.finish(reply)
+    }
+
+    /// Receive answer of the last command into `reply`,
+    /// non zero result code is returned as error
+    pub fn finish<'r>(&mut self, reply: &'r mut [u8]) -> Result<Packet<'r>, BmLiteError> {
+        let len = self.receive(reply)?;
+        let answer = Packet::parse(&reply[..len])?;
+        match answer.result() {
+            Some(code) if code != 0 => Err(BmLiteError::Result(code)),
+            _ => Ok(answer),
+        }
+    }
+
+    /// Send application packet, frame by frame
+    pub fn send(&mut self, packet: &[u8]) -> Result<(), BmLiteError> {
+        let seq_len = packet.len().div_ceil(HCP_CHUNK).max(1);
+        if seq_len > u16::MAX as usize {
+            return Err(BmLiteError::BufferTooSmall);
+        }
+
+        let mut frame = [0u8; TRANSPORT_HEADER + HCP_CHUNK];
+        let mut chunks = packet.chunks(HCP_CHUNK);
+        for seq_nr in 1..=seq_len {
+            let chunk = chunks.next().unwrap_or(&[]);
+            let mut header = [0u8; TRANSPORT_HEADER];
+            TransportHeader {
+                size: chunk.len() as u16,
+                seq_nr: seq_nr as u16,
+                seq_len: seq_len as u16,
+            }.encode(&mut header);
+            frame[..TRANSPORT_HEADER].copy_from_slice(&header);
+            frame[TRANSPORT_HEADER..TRANSPORT_HEADER + chunk.len()].copy_from_slice(chunk);
+
+            self.write_link(&frame[..TRANSPORT_HEADER + chunk.len()])?;
+            let mut ack = [0u8; 4];
+            if self.read_link(&mut ack)? != HCP_ACK.len() || ack != HCP_ACK {
+                return Err(BmLiteError::NoAck);
+            }
+        }
+        Ok(())
+    }
+
+    /// Receive application packet into `reply`, returns its length
+    pub fn receive(&mut self, reply: &mut [u8]) -> Result<usize, BmLiteError> {
+        let mut len = 0;
+        let mut frame = [0u8; TRANSPORT_HEADER + HCP_CHUNK];
+        loop {
+            let frame_len = self.read_link(&mut frame)?;
+            self.write_link(&HCP_ACK)?;
+
+            let (header, chunk) = TransportHeader::decode(&frame[..frame_len])?;
+            let end = len + chunk.len();
+            if end > reply.len() {
+                return Err(BmLiteError::BufferTooSmall);
+            }
+            reply[len..end].copy_from_slice(chunk);
+            len = end;
+
+            if header.seq_nr == header.seq_len {
+                return Ok(len);
+            }
+        }
+    }
+
+    fn write_link(&mut self, payload: &[u8]) -> Result<(), BmLiteError> {
+        let tx = unsafe { core::slice::from_raw_parts_mut(self.tx_buffor as *mut u8, SPI_BUF_LEN) };
+        let len = link_encode(payload, tx)?;
+
+        self.pins.cs.set_low().ok();
+        let result = self.spim.transfer(self.tx_buffor, len, self.rx_buffor, 0);
+        self.pins.cs.set_high().ok();
+        Ok(result?)
+    }
+
+    // Wait for IRQ, read header then the rest of the frame, returns payload length
+    fn read_link(&mut self, payload: &mut [u8]) -> Result<usize, BmLiteError> {
+        self.wait_irq()?;
+
+        self.pins.cs.set_low().ok();
+        let result = self.read_frame();
+        self.pins.cs.set_high().ok();
+        let len = result?;
+
+        let rx = unsafe { core::slice::from_raw_parts(self.rx_buffor as *const u8, len) };
+        let data = link_decode(rx)?;
+        payload.get_mut(..data.len()).ok_or(BmLiteError::BufferTooSmall)?.copy_from_slice(data);
+        Ok(data.len())
+    }
+
+    fn read_frame(&mut self) -> Result<usize, BmLiteError> {
+        self.spim.transfer(self.tx_buffor, 0, self.rx_buffor, LINK_HEADER)?;
+        let header = unsafe { core::slice::from_raw_parts(self.rx_buffor as *const u8, LINK_HEADER) };
+        let rest = link_payload_len(header)? + LINK_CRC;
+        if LINK_HEADER + rest > SPI_BUF_LEN {
+            return Err(BmLiteError::Frame);
+        }
+        self.spim.transfer(self.tx_buffor, 0, self.rx_buffor + LINK_HEADER as u32, rest)?;
+        Ok(LINK_HEADER + rest)
+    }
+
+    fn wait_irq(&self) -> Result<(), BmLiteError> {
+        for _ in 0..self.timeout_ms {
+            if self.is_irq() {
+                return Ok(());
+            }
+            cortex_m::asm::delay(CYCLES_PER_MS);
+        }
+        if self.is_irq() { Ok(()) } else { Err(BmLiteError::Timeout) }
+    }
+}
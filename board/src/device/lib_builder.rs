@@ -0,0 +1,494 @@
+// Board configuration, `init_board` is `BoardBuilder::new().build()`
+//
+// Pins are numbered `port * 32 + pin`, see `pin()`. Every optional
+// peripheral can be left out, it's then neither configured nor powered
+// and the matching `Device` field is `None`.
+//
+// `new()` starts from the profile picked by cargo feature: `dk` (default),
+// `dongle` or `custom`.
+
+#[cfg(not(any(feature = "dk", feature = "dongle", feature = "custom")))]
+compile_error!("Select board profile with one of the features: dk, dongle, custom");
+
+#[cfg(any(
+    all(feature = "dk", feature = "dongle"),
+    all(feature = "dk", feature = "custom"),
+    all(feature = "dongle", feature = "custom"),
+))]
+compile_error!("Only one board profile feature can be enabled, use `default-features = false`");
+
+use crate::hal_main as hal;
+use crate::device::*;
+
+
+/// Pin number as used by `BoardBuilder`, `pin(1, 2)` is P1.02
+pub const fn pin(port: u8, pin: u8) -> u8 {
+    port * 32 + pin
+}
+
+// P0.09 and P0.10 are the NFC antenna while NFCT is on
+const NFC_PINS: u64 = 1 << 9 | 1 << 10;
+
+
+#[derive(Clone, Copy)]
+pub struct UarteSetup {
+    pub rxd: u8,
+    pub txd: u8,
+    pub parity: Parity,
+    pub baudrate: Baudrate,
+}
+
+#[derive(Clone, Copy)]
+pub struct I2cSetup {
+    pub scl: u8,
+    pub sda: u8,
+    pub frequency: twim::Frequency,
+    pub config: I2cConfig,
+}
+
+#[derive(Clone, Copy)]
+pub struct TwisSetup {
+    pub scl: u8,
+    pub sda: u8,
+    pub address: u8,
+}
+
+#[derive(Clone, Copy)]
+pub struct SpimSetup {
+    pub sck: u8,
+    pub mosi: Option<u8>,
+    pub miso: Option<u8>,
+    pub frequency: Frequency,
+    pub mode: Mode,
+    /// Byte clocked out when there's nothing to send
+    pub orc: u8,
+}
+
+#[derive(Clone, Copy)]
+pub struct BmLiteSetup {
+    pub cs: u8,
+    pub rst: u8,
+    pub irq: u8,
+}
+
+
+pub struct BoardBuilder {
+    hf_clock: HfClockSource,
+    lf_clock: LfClockSource,
+    clock_fallback: bool,
+    leds: [u8; 4],
+    led_polarity: LedPolarity,
+    buttons: [u8; 4],
+    cts: Option<u8>,
+    uarte: Option<UarteSetup>,
+    i2c: Option<I2cSetup>,
+    twis: Option<TwisSetup>,
+    spim: Option<SpimSetup>,
+    bmlite: Option<BmLiteSetup>,
+    spi: Option<SpimSetup>,
+    spi_cs: Option<[u8; 2]>,
+    nfct: bool,
+    pwm: bool,
+}
+
+impl BoardBuilder {
+    /// Profile of the selected board feature
+    pub fn new() -> Self {
+        #[cfg(feature = "dk")]
+        return Self::dk();
+        #[cfg(feature = "dongle")]
+        return Self::dongle();
+        #[cfg(feature = "custom")]
+        return Self::custom();
+    }
+
+    /// LEDs and buttons of the profile only, enable the rest one by one
+    pub fn bare() -> Self {
+        BoardBuilder {
+            cts: None,
+            uarte: None,
+            i2c: None,
+            twis: None,
+            spim: None,
+            bmlite: None,
+            spi: None,
+            spi_cs: None,
+            nfct: false,
+            ..Self::new()
+        }
+    }
+
+    /// nRF52840-DK: every peripheral on, VCOM UART, OLED on P1.01/P1.02,
+    /// BM-Lite on the Arduino header
+    pub fn dk() -> Self {
+        BoardBuilder {
+            hf_clock: HfClockSource::External,
+            lf_clock: LfClockSource::Crystal,
+            clock_fallback: true,
+            leds: [pin(0, 13), pin(0, 14), pin(0, 15), pin(0, 16)],
+            led_polarity: LedPolarity::ActiveLow,
+            buttons: [pin(0, 11), pin(0, 12), pin(0, 24), pin(0, 25)],
+            cts: Some(pin(0, 7)),
+            uarte: Some(UarteSetup {
+                rxd: pin(0, 8),
+                txd: pin(0, 6),
+                parity: Parity::EXCLUDED,
+                baudrate: Baudrate::BAUD115200,
+            }),
+            i2c: Some(I2cSetup {
+                scl: pin(1, 1),
+                sda: pin(1, 2),
+                frequency: twim::Frequency::K400,
+                config: I2cConfig::default(),
+            }),
+            twis: Some(TwisSetup { scl: pin(1, 4), sda: pin(1, 5), address: TWIS_ADDR }),
+            spim: Some(SpimSetup {
+                sck: pin(1, 15),
+                mosi: Some(pin(1, 13)),
+                miso: Some(pin(1, 14)),
+                frequency: Frequency::M4,
+                mode: MODE_0,
+                orc: 0x00,
+            }),
+            bmlite: Some(BmLiteSetup { cs: pin(1, 12), rst: pin(1, 10), irq: pin(1, 11) }),
+            spi: Some(SpimSetup {
+                sck: pin(0, 28),
+                mosi: Some(pin(0, 29)),
+                miso: Some(pin(0, 30)),
+                frequency: Frequency::M4,
+                mode: MODE_0,
+                orc: 0xFF,
+            }),
+            spi_cs: Some([pin(0, 31), pin(0, 4)]),
+            nfct: true,
+            pwm: false,
+        }
+    }
+
+    /// nRF52840 Dongle: LED1 and the RGB LED, SW1 plus three buttons to GND on
+    /// P1.00/P0.24/P0.22. No VCOM, UART goes to an external adapter on the
+    /// edge pins, no room for TWIS and BM-Lite.
+    pub fn dongle() -> Self {
+        BoardBuilder {
+            hf_clock: HfClockSource::External,
+            lf_clock: LfClockSource::Crystal,
+            clock_fallback: true,
+            leds: [pin(0, 6), pin(0, 8), pin(1, 9), pin(0, 12)],
+            led_polarity: LedPolarity::ActiveLow,
+            buttons: [pin(1, 6), pin(1, 0), pin(0, 24), pin(0, 22)],
+            cts: None,
+            uarte: Some(UarteSetup {
+                rxd: pin(0, 13),
+                txd: pin(0, 15),
+                parity: Parity::EXCLUDED,
+                baudrate: Baudrate::BAUD115200,
+            }),
+            i2c: Some(I2cSetup {
+                scl: pin(0, 17),
+                sda: pin(0, 20),
+                frequency: twim::Frequency::K400,
+                config: I2cConfig::default(),
+            }),
+            twis: None,
+            spim: None,
+            bmlite: None,
+            spi: Some(SpimSetup {
+                sck: pin(0, 29),
+                mosi: Some(pin(0, 31)),
+                miso: Some(pin(0, 2)),
+                frequency: Frequency::M4,
+                mode: MODE_0,
+                orc: 0xFF,
+            }),
+            spi_cs: Some([pin(1, 15), pin(1, 13)]),
+            // No antenna, P0.09/P0.10 stay GPIO
+            nfct: false,
+            pwm: false,
+        }
+    }
+
+    /// In-house PCB: DK pin map with active high LEDs and no VCOM CTS.
+    /// Change pins here when the layout moves away from the DK.
+    pub fn custom() -> Self {
+        BoardBuilder {
+            led_polarity: LedPolarity::ActiveHigh,
+            cts: None,
+            ..Self::dk()
+        }
+    }
+
+    pub fn clocks(mut self, hf: HfClockSource, lf: LfClockSource) -> Self {
+        self.hf_clock = hf;
+        self.lf_clock = lf;
+        self
+    }
+
+    /// Run from HFINT or LFRC when a crystal doesn't start, instead of failing
+    pub fn clock_fallback(mut self, enabled: bool) -> Self {
+        self.clock_fallback = enabled;
+        self
+    }
+
+    pub fn leds(mut self, pins: [u8; 4]) -> Self {
+        self.leds = pins;
+        self
+    }
+
+    pub fn led_polarity(mut self, polarity: LedPolarity) -> Self {
+        self.led_polarity = polarity;
+        self
+    }
+
+    pub fn buttons(mut self, pins: [u8; 4]) -> Self {
+        self.buttons = pins;
+        self
+    }
+
+    /// UARTE CTS line watched through GPIOTE
+    pub fn cts(mut self, pin: Option<u8>) -> Self {
+        self.cts = pin;
+        self
+    }
+
+    pub fn uarte(mut self, setup: Option<UarteSetup>) -> Self {
+        self.uarte = setup;
+        self
+    }
+
+    pub fn i2c(mut self, setup: Option<I2cSetup>) -> Self {
+        self.i2c = setup;
+        self
+    }
+
+    pub fn twis(mut self, setup: Option<TwisSetup>) -> Self {
+        self.twis = setup;
+        self
+    }
+
+    /// SPIM2, meant for the BM-Lite
+    pub fn spim(mut self, setup: Option<SpimSetup>) -> Self {
+        self.spim = setup;
+        self
+    }
+
+    pub fn bmlite(mut self, setup: Option<BmLiteSetup>) -> Self {
+        self.bmlite = setup;
+        self
+    }
+
+    /// SPIM3 with its two chip selects
+    pub fn spi(mut self, setup: Option<SpimSetup>, cs: Option<[u8; 2]>) -> Self {
+        self.spi = setup;
+        self.spi_cs = cs;
+        self
+    }
+
+    pub fn nfct(mut self, enabled: bool) -> Self {
+        self.nfct = enabled;
+        self
+    }
+
+    /// Hand out PWM0 for `PwmLeds`, off in every profile
+    pub fn pwm(mut self, enabled: bool) -> Self {
+        self.pwm = enabled;
+        self
+    }
+
+    pub fn build(self) -> Result<Device, BoardError> {
+        let Some(periph) = hal::pac::Peripherals::take() else {
+            return Err(BoardError::PeripheralsTaken);
+        };
+
+        // Check every pin before starting clocks and peripherals
+        let mut pins = PinAllocator { used: if self.nfct { NFC_PINS } else { 0 } };
+        let [led_1, led_2, led_3, led_4] = pins.take_all(self.leds)?;
+        let [button_1, button_2, button_3, button_4] = pins.take_all(self.buttons)?;
+        let cts = self.cts.map(|id| pins.take(id)).transpose()?;
+        let uarte_pins = self.uarte.map(|setup| pins.take_all([setup.rxd, setup.txd])).transpose()?;
+        let i2c_pins = self.i2c.map(|setup| pins.take_all([setup.scl, setup.sda])).transpose()?;
+        let twis_pins = self.twis.map(|setup| pins.take_all([setup.scl, setup.sda])).transpose()?;
+        let spim_pins = self.spim.map(|setup| pins.take_spim(&setup)).transpose()?;
+        let bmlite_pins = self.bmlite.map(|setup| pins.take_all([setup.cs, setup.rst, setup.irq])).transpose()?;
+        let spi_pins = self.spi.map(|setup| pins.take_spim(&setup)).transpose()?;
+        let spi_cs = self.spi_cs.map(|ids| pins.take_all(ids)).transpose()?;
+
+        // ********** POWER Configuration **********
+        // Cleared only once the build can't fail, a failed boot keeps the reason for the next one
+        let mut board_power = Power::new(periph.POWER, periph.LPCOMP);
+        let board_reset_reason = board_power.reset_reason();
+
+        // ********** CLOCK Configuration **********
+        // Missing or broken crystal must not hang the board
+        let board_clocks = BoardClocks::start(periph.CLOCK,
+            self.hf_clock, self.lf_clock, self.clock_fallback)?;
+
+        // ********** GPIO Configuration **********
+        let leds = Leds {
+            _1: Led { inner: led_1.into_push_pull_output(self.led_polarity.off_level()), polarity: self.led_polarity },
+            _2: Led { inner: led_2.into_push_pull_output(self.led_polarity.off_level()), polarity: self.led_polarity },
+            _3: Led { inner: led_3.into_push_pull_output(self.led_polarity.off_level()), polarity: self.led_polarity },
+            _4: Led { inner: led_4.into_push_pull_output(self.led_polarity.off_level()), polarity: self.led_polarity },
+        };
+
+        let buttons = Buttons {
+            _1: Button { inner: button_1.into_pullup_input() },
+            _2: Button { inner: button_2.into_pullup_input() },
+            _3: Button { inner: button_3.into_pullup_input() },
+            _4: Button { inner: button_4.into_pullup_input() },
+        };
+
+        // ********** GPIOTE Configuration **********
+        // Pins are registered by the application, see `GpioteManager`
+        let board_gpiote = Gpiote::new(periph.GPIOTE);
+
+        // CTS line of the DK's VCOM, falling edge announces incoming frame
+        let board_cts = cts.map(|pin| pin.into_floating_input());
+
+        // ********** UARTE configuration **********
+        let board_uarte = self.uarte.zip(uarte_pins).map(|(setup, [rxd, txd])| {
+            Uarte::new(periph.UARTE0,
+                uarte::Pins {
+                    rxd: rxd.into_floating_input(),
+                    txd: txd.into_push_pull_output(Level::High),
+                    cts: None,
+                    rts: None,
+                },
+                setup.parity,
+                setup.baudrate,
+            )
+        });
+
+        // ********** I2C Master configuration **********
+        let board_i2c = match self.i2c.zip(i2c_pins) {
+            Some((setup, [scl, sda])) => {
+                let i2c = I2c::new(periph.TWIM0,
+                    twim::Pins { scl: scl.into_floating_input(), sda: sda.into_floating_input() },
+                    setup.frequency,
+                    setup.config,
+                    );
+                // Something shorts SDA, bus clear didn't help
+                if i2c.is_bus_stuck() {
+                    return Err(BoardError::PeripheralInit(BoardPeripheral::I2c));
+                }
+                Some(i2c)
+            },
+            None => None,
+        };
+
+        // ********** I2C Slave configuration **********
+        // Register map is filled by the application
+        let board_twis = self.twis.zip(twis_pins).map(|(setup, [scl, sda])| {
+            Twis::new(periph.TWIS1,
+                twis::Pins { scl: scl.into_floating_input(), sda: sda.into_floating_input() },
+                setup.address,
+                TWIS_RX_BUF_DEF,
+                TWIS_TX_BUF_DEF,
+                )
+        });
+
+        // ********** SPI Master configuration **********
+        // BM-Lite on the Arduino header, CS/RST/IRQ are driven by `BmLite`
+        let board_spim = self.spim.zip(spim_pins).map(|(setup, pins)| {
+            Spim::new(periph.SPIM2, pins, setup.frequency, setup.mode, setup.orc)
+        });
+
+        let board_bmlite_pins = bmlite_pins.map(|[cs, rst, irq]| BmLitePins {
+            cs: cs.into_push_pull_output(Level::High),
+            rst: rst.into_push_pull_output(Level::High),
+            irq: irq.into_pulldown_input(),
+        });
+
+        // General purpose SPI, one `SpiDevice` per chip select
+        let board_spi = self.spi.zip(spi_pins).map(|(setup, pins)| {
+            Spim::new(periph.SPIM3, pins, setup.frequency, setup.mode, setup.orc)
+        });
+
+        let board_spi_cs = spi_cs.map(|[cs_1, cs_2]| SpiSelects {
+            _1: cs_1.into_push_pull_output(Level::High),
+            _2: cs_2.into_push_pull_output(Level::High),
+        });
+
+        // ********** New DMA BUFFOR ****************
+        let board_dma = DmaBuffor::new();
+        unsafe {board_dma.ptr.uarte_tx.write([0x0A, 0x31, 0x32, 0x33]); }
+
+        // ********** NFCT configuration **********
+        let board_nfct = self.nfct.then(|| Nfct::new(periph.NFCT));
+
+        let board_pwm = self.pwm.then_some(periph.PWM0);
+
+        let board_timers = Timers {
+            tim0: Timer::new(periph.TIMER0),
+            tim1: HwTimer::new(periph.TIMER1),
+            tim2: HwTimer::new(periph.TIMER2),
+            tim3: HwTimer::new(periph.TIMER3),
+        };
+
+        let board_ppi = Ppi::new(periph.PPI).ok_or(BoardError::PeripheralsTaken)?;
+
+        board_power.clear_reset_reason(board_reset_reason);
+
+        Ok(Device {
+            board_clocks,
+            leds,
+            buttons,
+            board_gpiote,
+            board_cts,
+            board_nfct,
+            board_uarte,
+            board_i2c,
+            board_twis,
+            board_spim,
+            board_bmlite_pins,
+            board_spi,
+            board_spi_cs,
+            board_dma,
+            board_timers,
+            board_pwm,
+            board_rtc: periph.RTC1,
+            board_ppi,
+            board_reset_reason,
+            board_power,
+        })
+    }
+}
+
+impl Default for BoardBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+
+// Hands every pin out once
+struct PinAllocator {
+    used: u64,
+}
+
+impl PinAllocator {
+    fn take(&mut self, id: u8) -> Result<Pin<Disconnected>, BoardError> {
+        // nRF52840 has P0.00-P0.31 and P1.00-P1.15
+        if id >= 48 || self.used & 1 << id != 0 {
+            return Err(BoardError::InvalidPin(id));
+        }
+        self.used |= 1 << id;
+        // Pin is exclusively ours, P0 and P1 went with `Peripherals::take`
+        Ok(unsafe { Pin::from_psel_bits(id as u32) })
+    }
+
+    fn take_all<const N: usize>(&mut self, ids: [u8; N]) -> Result<[Pin<Disconnected>; N], BoardError> {
+        let pins = ids.map(|id| self.take(id));
+        if let Some(Err(error)) = pins.iter().find(|pin| pin.is_err()) {
+            return Err(*error);
+        }
+        Ok(pins.map(|pin| pin.unwrap()))
+    }
+
+    fn take_spim(&mut self, setup: &SpimSetup) -> Result<spim::Pins, BoardError> {
+        Ok(spim::Pins {
+            sck: self.take(setup.sck)?.into_push_pull_output(Level::Low),
+            mosi: setup.mosi.map(|id| self.take(id)).transpose()?.map(|pin| pin.into_push_pull_output(Level::Low)),
+            miso: setup.miso.map(|id| self.take(id)).transpose()?.map(|pin| pin.into_floating_input()),
+        })
+    }
+}
@@ -0,0 +1,101 @@
+// Shared I2C bus
+//
+// The bus is an RTIC shared resource (not `#[lock_free]` when used from
+// several priorities). Each device gets an `I2cProxy` bound to its address
+// and its own DMA buffer, the proxy is handed to the driver; every transfer
+// locks the resource only for its own duration, so tasks above the bus
+// ceiling are never blocked.
+//
+// Written bytes are copied into the proxy buffer under the lock, so a task
+// preempting the driver can't change them between staging and transfer.
+
+use crate::device::I2cError;
+use embedded_hal::blocking::i2c::{Read, Write, WriteRead};
+pub use rtic_core::{Exclusive, Mutex};
+
+
+/// embedded-hal I2C for one device on a bus guarded by any `Mutex`
+pub struct I2cProxy<M> {
+    bus: M,
+    address: u8,
+    buffor: u32,
+    len: usize,
+}
+
+impl<M: Mutex> I2cProxy<M> {
+    /// `bus` is a RTIC shared resource, or `Exclusive` where nothing else uses the bus;
+    /// `buffor` is RAM address of `len` bytes used by this proxy only, not the
+    /// buffer the driver stages into (e.g. `I2C_PROXY_BUF`)
+    pub fn new(bus: M, address: u8, buffor: u32, len: usize) -> Self {
+        I2cProxy { bus, address, buffor, len }
+    }
+
+    pub fn address(&self) -> u8 {
+        self.address
+    }
+
+    pub fn free(self) -> M {
+        self.bus
+    }
+
+    fn check(&self, address: u8) -> Result<(), I2cError> {
+        if address == self.address { Ok(()) } else { Err(I2cError::WrongDevice) }
+    }
+}
+
+// Copy `bytes` into the proxy buffer
+fn stage((buffor, len): (u32, usize), bytes: &[u8]) -> Result<&'static [u8], I2cError> {
+    if bytes.len() > len {
+        return Err(I2cError::BufferTooLong);
+    }
+    let buffor = buffor as *mut u8;
+    unsafe { core::ptr::copy_nonoverlapping(bytes.as_ptr(), buffor, bytes.len()) };
+    Ok(unsafe { core::slice::from_raw_parts(buffor, bytes.len()) })
+}
+
+impl<M, B> Write for I2cProxy<M>
+where
+    M: Mutex<T = B>,
+    B: Write<Error = I2cError>,
+{
+    type Error = I2cError;
+
+    fn write(&mut self, address: u8, bytes: &[u8]) -> Result<(), I2cError> {
+        self.check(address)?;
+        let buffor = (self.buffor, self.len);
+        self.bus.lock(|bus| {
+            let staged = stage(buffor, bytes)?;
+            bus.write(address, staged)
+        })
+    }
+}
+
+impl<M, B> Read for I2cProxy<M>
+where
+    M: Mutex<T = B>,
+    B: Read<Error = I2cError>,
+{
+    type Error = I2cError;
+
+    fn read(&mut self, address: u8, buffer: &mut [u8]) -> Result<(), I2cError> {
+        self.check(address)?;
+        self.bus.lock(|bus| bus.read(address, buffer))
+    }
+}
+
+impl<M, B> WriteRead for I2cProxy<M>
+where
+    M: Mutex<T = B>,
+    B: WriteRead<Error = I2cError>,
+{
+    type Error = I2cError;
+
+    fn write_read(&mut self, address: u8, bytes: &[u8], buffer: &mut [u8]) -> Result<(), I2cError> {
+        self.check(address)?;
+        let buffor = (self.buffor, self.len);
+        self.bus.lock(|bus| {
+            let staged = stage(buffor, bytes)?;
+            bus.write_read(address, staged, buffer)
+        })
+    }
+}
@@ -0,0 +1,505 @@
+// Button gesture engine
+//
+// Pure timing logic, no peripherals inside - feed it the pressed mask from
+// `Buttons::pressed_mask()` and a millisecond timestamp from the monotonic.
+
+use crate::device::{remaining, TickPlan};
+
+pub const BUTTON_COUNT: usize = 4;
+const EVENT_QUEUE_LEN: usize = 16;
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum ButtonEvent {
+    /// Button (1..=4) went down
+    Pressed(u8),
+    /// Button (1..=4) went up
+    Released(u8),
+    /// Short press not followed by a second one in time
+    Click(u8),
+    /// Two short presses within `double_click_ms`
+    DoubleClick(u8),
+    /// Button held for at least `long_press_ms`, with held time in ms
+    LongPress(u8, u32),
+    /// Two or more buttons held together, bit 0 is button 1
+    Chord(u8),
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct GestureConfig {
+    /// Max time between release and next press to count as double click
+    pub double_click_ms: u32,
+    /// Time a button has to be held to report long press
+    pub long_press_ms: u32,
+}
+
+impl Default for GestureConfig {
+    fn default() -> Self {
+        GestureConfig {
+            double_click_ms: 300,
+            long_press_ms: 800,
+        }
+    }
+}
+
+
+#[derive(Clone, Copy, Default)]
+struct ButtonState {
+    pressed: bool,
+    pressed_at: u32,
+    released_at: u32,
+    // Number of short presses waiting for double click decision
+    clicks: u8,
+    // Long press or chord already consumed this press
+    consumed: bool,
+}
+
+pub struct GestureEngine {
+    config: GestureConfig,
+    mask: u8,
+    chord: u8,
+    buttons: [ButtonState; BUTTON_COUNT],
+    queue: [Option<ButtonEvent>; EVENT_QUEUE_LEN],
+    head: usize,
+    len: usize,
+}
+
+impl GestureEngine {
+    pub fn new(config: GestureConfig) -> Self {
+        GestureEngine {
+            config,
+            mask: 0,
+            chord: 0,
+            buttons: [ButtonState::default(); BUTTON_COUNT],
+            queue: [None; EVENT_QUEUE_LEN],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    pub fn config(&self) -> GestureConfig {
+        self.config
+    }
+
+    pub fn set_config(&mut self, config: GestureConfig) {
+        self.config = config;
+    }
+
+    /// Currently pressed buttons as seen by the engine
+    pub fn mask(&self) -> u8 {
+        self.mask
+    }
+
+    /// Feed new state of the buttons, call on every (debounced) pin change
+    pub fn update(&mut self, now: u32, mask: u8) {
+        // Timeouts which expired before this change have to go first
+        self.poll(now);
+
+        let changed = self.mask ^ mask;
+        self.mask = mask;
+
+        for i in 0..BUTTON_COUNT {
+            let bit = 1 << i;
+            if changed & bit == 0 {
+                continue;
+            }
+            let id = i as u8 + 1;
+
+            if mask & bit != 0 {
+                let state = &mut self.buttons[i];
+                state.pressed = true;
+                state.pressed_at = now;
+                state.consumed = false;
+                self.push(ButtonEvent::Pressed(id));
+            } else {
+                self.push(ButtonEvent::Released(id));
+                self.release(i, now);
+            }
+        }
+
+        // New button joined already held ones
+        if mask.count_ones() >= 2 && mask & !self.chord != 0 {
+            self.chord = mask;
+            for (i, state) in self.buttons.iter_mut().enumerate() {
+                if mask & (1 << i) != 0 {
+                    state.consumed = true;
+                    state.clicks = 0;
+                }
+            }
+            self.push(ButtonEvent::Chord(mask));
+        }
+        if mask == 0 {
+            self.chord = 0;
+        }
+    }
+
+    /// Resolve time based gestures, call when `next_deadline()` expires
+    pub fn poll(&mut self, now: u32) {
+        for i in 0..BUTTON_COUNT {
+            let id = i as u8 + 1;
+            let state = self.buttons[i];
+
+            if state.pressed && !state.consumed {
+                let held = now.wrapping_sub(state.pressed_at);
+                if held >= self.config.long_press_ms {
+                    self.buttons[i].consumed = true;
+                    self.buttons[i].clicks = 0;
+                    self.push(ButtonEvent::LongPress(id, held));
+                }
+            } else if !state.pressed && state.clicks > 0
+                && now.wrapping_sub(state.released_at) >= self.config.double_click_ms {
+                self.buttons[i].clicks = 0;
+                self.push(ButtonEvent::Click(id));
+            }
+        }
+    }
+
+    /// Milliseconds from `now` to the next timeout, `None` if nothing is waiting
+    pub fn next_deadline(&self, now: u32) -> Option<u32> {
+        self.buttons.iter()
+            .filter_map(|state| {
+                if state.pressed && !state.consumed {
+                    Some(state.pressed_at.wrapping_add(self.config.long_press_ms))
+                } else if !state.pressed && state.clicks > 0 {
+                    Some(state.released_at.wrapping_add(self.config.double_click_ms))
+                } else {
+                    None
+                }
+            })
+            .map(|deadline| remaining(deadline, now))
+            .min()
+    }
+
+    /// Take the oldest event
+    pub fn pop(&mut self) -> Option<ButtonEvent> {
+        if self.len == 0 {
+            return None;
+        }
+        let event = self.queue[self.head].take();
+        self.head = (self.head + 1) % EVENT_QUEUE_LEN;
+        self.len -= 1;
+        event
+    }
+
+    fn release(&mut self, i: usize, now: u32) {
+        let id = i as u8 + 1;
+        let state = &mut self.buttons[i];
+        state.pressed = false;
+        state.released_at = now;
+
+        if state.consumed {
+            return;
+        }
+        state.clicks += 1;
+        if state.clicks >= 2 {
+            state.clicks = 0;
+            self.push(ButtonEvent::DoubleClick(id));
+        }
+    }
+
+    // Oldest event is dropped when the queue is full
+    fn push(&mut self, event: ButtonEvent) {
+        if self.len == EVENT_QUEUE_LEN {
+            self.pop();
+        }
+        let tail = (self.head + self.len) % EVENT_QUEUE_LEN;
+        self.queue[tail] = Some(event);
+        self.len += 1;
+    }
+}
+
+impl Default for GestureEngine {
+    fn default() -> Self {
+        GestureEngine::new(GestureConfig::default())
+    }
+}
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum DebounceResult {
+    /// Edges were still coming, sample again after `settle_ms`
+    Resample,
+    /// Pins are quiet, new stable mask
+    Changed(u8),
+    /// Pins are quiet, bounce ended where it started
+    Unchanged,
+}
+
+/// Coalesces bursts of GPIOTE edges into one sample taken after pins went quiet
+pub struct Debouncer {
+    settle_ms: u32,
+    stable: u8,
+    pending: bool,
+    bouncing: bool,
+}
+
+impl Debouncer {
+    pub fn new(settle_ms: u32) -> Self {
+        Debouncer {
+            settle_ms,
+            stable: 0,
+            pending: false,
+            bouncing: false,
+        }
+    }
+
+    pub fn settle_ms(&self) -> u32 {
+        self.settle_ms
+    }
+
+    /// Last stable mask
+    pub fn stable(&self) -> u8 {
+        self.stable
+    }
+
+    /// Call on every edge; `true` means a sample has to be scheduled,
+    /// `false` means one is already pending and the edge is absorbed by it
+    pub fn edge(&mut self) -> bool {
+        if self.pending {
+            self.bouncing = true;
+            return false;
+        }
+        self.pending = true;
+        self.bouncing = false;
+        true
+    }
+
+    /// Scheduling of the sample failed, next edge will try again
+    pub fn abort(&mut self) {
+        self.pending = false;
+    }
+
+    /// Call `settle_ms` after scheduling with the raw pressed mask
+    pub fn sample(&mut self, raw: u8) -> DebounceResult {
+        if self.bouncing {
+            self.bouncing = false;
+            return DebounceResult::Resample;
+        }
+        self.pending = false;
+
+        if raw == self.stable {
+            DebounceResult::Unchanged
+        } else {
+            self.stable = raw;
+            DebounceResult::Changed(raw)
+        }
+    }
+}
+
+impl Default for Debouncer {
+    fn default() -> Self {
+        Debouncer::new(20)
+    }
+}
+
+
+/// Debouncer and gesture engine wired together, the application only spawns
+/// the tasks it is told to
+///
+/// GPIOTE PORT event -> `edge`, sample task -> `Buttons::debounce`, gesture
+/// timeout task -> `poll`, event task drains `pop` and ends with `plan_tick`.
+/// `schedule` callbacks spawn a task after the given milliseconds and return
+/// `false` if that failed.
+pub struct ButtonPipeline {
+    debouncer: Debouncer,
+    gestures: GestureEngine,
+    tick: TickPlan,
+}
+
+impl ButtonPipeline {
+    pub fn new(settle_ms: u32, config: GestureConfig) -> Self {
+        ButtonPipeline {
+            debouncer: Debouncer::new(settle_ms),
+            gestures: GestureEngine::new(config),
+            tick: TickPlan::new(),
+        }
+    }
+
+    pub fn set_config(&mut self, config: GestureConfig) {
+        self.gestures.set_config(config);
+    }
+
+    /// Button pin changed, `schedule` spawns the sample task
+    pub fn edge(&mut self, schedule: impl FnOnce(u32) -> bool) {
+        if self.debouncer.edge() && !schedule(self.debouncer.settle_ms) {
+            self.debouncer.abort();
+        }
+    }
+
+    /// Sample task with the raw pressed mask, `schedule` spawns it again
+    /// while pins bounce; returns `true` when events may be waiting
+    pub fn sample(&mut self, now: u32, raw: u8, schedule: impl FnOnce(u32) -> bool) -> bool {
+        match self.debouncer.sample(raw) {
+            DebounceResult::Resample => {
+                if !schedule(self.debouncer.settle_ms) {
+                    self.debouncer.abort();
+                }
+                false
+            },
+            DebounceResult::Changed(mask) => {
+                self.gestures.update(now, mask);
+                true
+            },
+            DebounceResult::Unchanged => false,
+        }
+    }
+
+    /// Gesture timeout task spawned for `deadline` fired, returns `true` when
+    /// events may be waiting; stale timeouts return `false`
+    pub fn poll(&mut self, now: u32, deadline: u32) -> bool {
+        if !self.tick.fired(deadline) {
+            return false;
+        }
+        self.gestures.poll(now);
+        true
+    }
+
+    pub fn pop(&mut self) -> Option<ButtonEvent> {
+        self.gestures.pop()
+    }
+
+    /// `schedule(left, deadline)` spawns the gesture timeout task carrying
+    /// `deadline` when the closest one moved, see `TickPlan`
+    pub fn plan_tick(&mut self, now: u32, schedule: impl FnOnce(u32, u32) -> bool) {
+        self.tick.plan(now, self.gestures.next_deadline(now), schedule);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const B1: u8 = 0b0001;
+    const B2: u8 = 0b0010;
+
+    fn events(engine: &mut GestureEngine) -> Vec<ButtonEvent> {
+        core::iter::from_fn(|| engine.pop()).collect()
+    }
+
+    #[test]
+    fn click_after_double_click_window() {
+        let mut engine = GestureEngine::default();
+        engine.update(0, B1);
+        engine.update(100, 0);
+        assert_eq!(events(&mut engine), [ButtonEvent::Pressed(1), ButtonEvent::Released(1)]);
+
+        assert_eq!(engine.next_deadline(100), Some(300));
+        engine.poll(399);
+        assert_eq!(events(&mut engine), []);
+        engine.poll(400);
+        assert_eq!(events(&mut engine), [ButtonEvent::Click(1)]);
+        assert_eq!(engine.next_deadline(400), None);
+    }
+
+    #[test]
+    fn double_click_within_window() {
+        let mut engine = GestureEngine::default();
+        engine.update(0, B1);
+        engine.update(50, 0);
+        engine.update(200, B1);
+        engine.update(250, 0);
+        assert_eq!(events(&mut engine), [
+            ButtonEvent::Pressed(1), ButtonEvent::Released(1),
+            ButtonEvent::Pressed(1), ButtonEvent::Released(1),
+            ButtonEvent::DoubleClick(1),
+        ]);
+        engine.poll(1_000);
+        assert_eq!(events(&mut engine), []);
+    }
+
+    #[test]
+    fn long_press_at_threshold() {
+        let mut engine = GestureEngine::default();
+        engine.update(0, B2);
+        events(&mut engine);
+
+        assert_eq!(engine.next_deadline(0), Some(800));
+        engine.poll(799);
+        assert_eq!(events(&mut engine), []);
+        engine.poll(800);
+        assert_eq!(events(&mut engine), [ButtonEvent::LongPress(2, 800)]);
+
+        // Consumed press gives no click on release
+        engine.update(1_500, 0);
+        engine.poll(2_000);
+        assert_eq!(events(&mut engine), [ButtonEvent::Released(2)]);
+    }
+
+    #[test]
+    fn hold_reports_long_press_once() {
+        let mut engine = GestureEngine::default();
+        engine.update(0, B1);
+        events(&mut engine);
+        engine.poll(900);
+        engine.poll(5_000);
+        assert_eq!(events(&mut engine), [ButtonEvent::LongPress(1, 900)]);
+        assert_eq!(engine.next_deadline(5_000), None);
+    }
+
+    #[test]
+    fn long_press_across_timer_wrap() {
+        let mut engine = GestureEngine::default();
+        let start = u32::MAX - 100;
+        engine.update(start, B1);
+        events(&mut engine);
+        engine.poll(start.wrapping_add(800));
+        assert_eq!(events(&mut engine), [ButtonEvent::LongPress(1, 800)]);
+    }
+
+    #[test]
+    fn chord_consumes_clicks() {
+        let mut engine = GestureEngine::default();
+        engine.update(0, B1);
+        engine.update(20, B1 | B2);
+        engine.update(100, 0);
+        engine.poll(1_000);
+        assert_eq!(events(&mut engine), [
+            ButtonEvent::Pressed(1), ButtonEvent::Pressed(2), ButtonEvent::Chord(B1 | B2),
+            ButtonEvent::Released(1), ButtonEvent::Released(2),
+        ]);
+    }
+
+    #[test]
+    fn debouncer_resamples_while_bouncing() {
+        let mut debouncer = Debouncer::new(20);
+        assert!(debouncer.edge());
+        assert!(!debouncer.edge());
+        assert_eq!(debouncer.sample(B1), DebounceResult::Resample);
+        assert_eq!(debouncer.sample(B1), DebounceResult::Changed(B1));
+        assert!(debouncer.edge());
+        assert_eq!(debouncer.sample(B1), DebounceResult::Unchanged);
+    }
+
+    #[test]
+    fn pipeline_plans_each_deadline_once() {
+        let mut pipeline = ButtonPipeline::new(20, GestureConfig::default());
+        let mut planned = Vec::new();
+
+        pipeline.edge(|ms| { planned.push(ms); true });
+        pipeline.edge(|_| panic!("sample already pending"));
+        assert!(!pipeline.sample(20, B1, |ms| { planned.push(ms); true }));
+        assert_eq!(planned, [20, 20]);
+
+        assert!(pipeline.sample(40, B1, |_| panic!("pins are quiet")));
+        assert_eq!(pipeline.pop(), Some(ButtonEvent::Pressed(1)));
+        pipeline.plan_tick(40, |ms, _| { planned.push(ms); true });
+        pipeline.plan_tick(45, |_, _| panic!("deadline didn't move"));
+        assert_eq!(planned, [20, 20, 800]);
+
+        assert!(!pipeline.poll(840, 820));
+        assert_eq!(pipeline.pop(), None);
+        assert!(pipeline.poll(840, 840));
+        assert_eq!(pipeline.pop(), Some(ButtonEvent::LongPress(1, 800)));
+        pipeline.plan_tick(840, |_, _| panic!("nothing is waiting"));
+    }
+
+    #[test]
+    fn pipeline_retries_failed_schedule() {
+        let mut pipeline = ButtonPipeline::new(20, GestureConfig::default());
+        pipeline.edge(|_| false);
+        let mut retried = false;
+        pipeline.edge(|_| { retried = true; true });
+        assert!(retried);
+    }
+}
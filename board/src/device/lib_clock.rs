@@ -0,0 +1,269 @@
+// HFCLK/LFCLK ownership
+//
+// HFXO can be kept on by the board configuration or requested by drivers
+// (radio, USB, LFRC calibration); it stops again when the last user releases
+// it. Without 32.768 kHz crystal LFCLK runs from RC, which drifts with
+// temperature and needs calibration against HFXO every few seconds. The
+// calibration starts HFXO without waiting and runs from the POWER_CLOCK
+// interrupt, so the ISR never spins on the crystal.
+
+use crate::hal_main as hal;
+use crate::device::BoardError;
+use hal::pac::CLOCK;
+
+
+const HFXO_TIMEOUT_MS: u32 = 100;
+const LFCLK_TIMEOUT_MS: u32 = 1_000;
+/// Default LFRC calibration period, 0.25 s units (4 s)
+pub const LFRC_CAL_INTERVAL: u8 = 16;
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum HfClockSource {
+    /// 64 MHz RC, started on demand by the peripherals
+    Internal,
+    /// 32 MHz crystal, needed for radio and USB
+    External,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum LfClockSource {
+    Rc,
+    Crystal,
+    /// Derived from HFCLK, costs the HF clock running all the time
+    Synthesized,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum ClockEvent {
+    /// Calibration timer expired, HFXO is starting for LFRC calibration
+    CalibrationStarted,
+    CalibrationDone,
+    /// HFXO didn't start until the next calibration period, attempt dropped
+    CalibrationFailed,
+}
+
+
+pub struct BoardClocks {
+    clock: CLOCK,
+    hf_source: HfClockSource,
+    lf_source: LfClockSource,
+    // Board configuration keeps HFXO on regardless of requests
+    hfxo_pinned: bool,
+    hfxo_users: u8,
+    calibrating: bool,
+    // Calibration waits for HFCLKSTARTED
+    hfxo_pending: bool,
+}
+
+impl BoardClocks {
+    /// Start clocks with bounded wait, a missing crystal is reported instead
+    /// of spinning forever. With `fallback` a crystal that doesn't start is
+    /// replaced by HFINT or LFRC, check `hf_source()` and `lf_source()`.
+    pub fn start(clock: CLOCK, hf: HfClockSource, lf: LfClockSource, fallback: bool)
+        -> Result<Self, BoardError>
+    {
+        // Synthesized LFCLK needs the crystal as well
+        let hfxo_pinned = hf == HfClockSource::External || lf == LfClockSource::Synthesized;
+        let mut clocks = BoardClocks {
+            clock,
+            hf_source: hf,
+            lf_source: lf,
+            hfxo_pinned,
+            hfxo_users: 0,
+            calibrating: false,
+            hfxo_pending: false,
+        };
+
+        if hfxo_pinned {
+            match clocks.start_hfxo() {
+                Err(BoardError::HfxoTimeout) if fallback => {
+                    // Peripherals start HFINT on demand, synthesized LFCLK goes to RC
+                    clocks.hfxo_pinned = false;
+                    clocks.hf_source = HfClockSource::Internal;
+                    if lf == LfClockSource::Synthesized {
+                        clocks.lf_source = LfClockSource::Rc;
+                    }
+                },
+                result => result?,
+            }
+        }
+
+        let lf = clocks.lf_source;
+        match clocks.start_lfclk(lf) {
+            Err(BoardError::LfclkTimeout) if lf == LfClockSource::Crystal && fallback => {
+                clocks.clock.tasks_lfclkstop.write(|w| unsafe { w.bits(1) });
+                clocks.start_lfclk(LfClockSource::Rc)?;
+                clocks.lf_source = LfClockSource::Rc;
+            },
+            result => result?,
+        }
+        Ok(clocks)
+    }
+
+    /// Give the peripheral back, clocks keep running
+    pub fn free(self) -> CLOCK {
+        self.clock
+    }
+
+    pub fn hf_source(&self) -> HfClockSource {
+        self.hf_source
+    }
+
+    /// Source LFCLK really runs from, RC after crystal fallback
+    pub fn lf_source(&self) -> LfClockSource {
+        self.lf_source
+    }
+
+    pub fn is_hfxo_running(&self) -> bool {
+        let stat = self.clock.hfclkstat.read();
+        stat.src().is_xtal() && stat.state().is_running()
+    }
+
+    pub fn is_lfclk_running(&self) -> bool {
+        self.clock.lfclkstat.read().state().is_running()
+    }
+
+    /// Keep HFXO on until matching `release_hfxo`, blocks until it's started
+    pub fn request_hfxo(&mut self) -> Result<(), BoardError> {
+        // A calibration may hold HFXO which is still starting
+        if (self.hfxo_users == 0 || self.hfxo_pending) && !self.hfxo_pinned {
+            self.start_hfxo()?;
+        }
+        self.hfxo_users = self.hfxo_users.saturating_add(1);
+        Ok(())
+    }
+
+    /// HFXO stops with the last release, unless the board configuration needs it
+    pub fn release_hfxo(&mut self) {
+        self.hfxo_users = self.hfxo_users.saturating_sub(1);
+        if self.hfxo_users == 0 && !self.hfxo_pinned {
+            self.clock.tasks_hfclkstop.write(|w| unsafe { w.bits(1) });
+        }
+    }
+
+    pub fn hfxo_users(&self) -> u8 {
+        self.hfxo_users
+    }
+
+    /// Calibrate LFRC once, `on_interrupt` reports `CalibrationDone`.
+    /// Doesn't wait for HFXO, calibration starts from `on_interrupt` once it
+    /// runs. Does nothing unless LFCLK runs from RC.
+    pub fn calibrate(&mut self) {
+        if self.lf_source != LfClockSource::Rc || self.calibrating {
+            return;
+        }
+        self.calibrating = true;
+        let running = self.hfxo_pinned || self.hfxo_users > 0;
+        self.hfxo_users = self.hfxo_users.saturating_add(1);
+        if running {
+            self.start_calibration();
+        } else {
+            self.hfxo_pending = true;
+            self.clock.events_hfclkstarted.reset();
+            self.clock.intenset.write(|w| w.hfclkstarted().set());
+            self.clock.tasks_hfclkstart.write(|w| unsafe { w.bits(1) });
+        }
+    }
+
+    /// Calibrate LFRC every `interval` x 0.25 s (1..=127), needs POWER_CLOCK
+    /// interrupt forwarded to `on_interrupt`. Does nothing unless LFCLK runs
+    /// from RC.
+    pub fn start_calibration_timer(&mut self, interval: u8) {
+        if self.lf_source != LfClockSource::Rc {
+            return;
+        }
+        self.clock.ctiv.write(|w| unsafe { w.ctiv().bits(interval.clamp(1, 127)) });
+        self.clock.events_ctto.reset();
+        self.clock.intenset.write(|w| w.ctto().set());
+        self.clock.tasks_ctstart.write(|w| unsafe { w.bits(1) });
+    }
+
+    pub fn stop_calibration_timer(&mut self) {
+        self.clock.intenclr.write(|w| w.ctto().clear());
+        self.clock.tasks_ctstop.write(|w| unsafe { w.bits(1) });
+    }
+
+    pub fn is_calibrating(&self) -> bool {
+        self.calibrating
+    }
+
+    /// Call from POWER_CLOCK interrupt, never waits
+    pub fn on_interrupt(&mut self) -> Option<ClockEvent> {
+        // A blocking `request_hfxo` in between may have taken the event already
+        if self.hfxo_pending
+            && (self.clock.events_hfclkstarted.read().bits() != 0 || self.is_hfxo_running())
+        {
+            self.clock.events_hfclkstarted.reset();
+            self.clock.intenclr.write(|w| w.hfclkstarted().clear());
+            self.hfxo_pending = false;
+            self.start_calibration();
+            return None;
+        }
+
+        if self.clock.events_done.read().bits() != 0 {
+            self.clock.events_done.reset();
+            self.clock.intenclr.write(|w| w.done().clear());
+            self.calibrating = false;
+            self.release_hfxo();
+            return Some(ClockEvent::CalibrationDone);
+        }
+
+        if self.clock.events_ctto.read().bits() != 0 {
+            self.clock.events_ctto.reset();
+            // Timer stops itself on timeout, keep it going for the next period
+            self.clock.tasks_ctstart.write(|w| unsafe { w.bits(1) });
+            if self.hfxo_pending {
+                // Crystal didn't come up for a whole period, try again next time
+                self.clock.intenclr.write(|w| w.hfclkstarted().clear());
+                self.hfxo_pending = false;
+                self.calibrating = false;
+                self.release_hfxo();
+                return Some(ClockEvent::CalibrationFailed);
+            }
+            if self.calibrating {
+                return None;
+            }
+            self.calibrate();
+            return Some(ClockEvent::CalibrationStarted);
+        }
+        None
+    }
+
+    fn start_calibration(&mut self) {
+        self.clock.events_done.reset();
+        self.clock.intenset.write(|w| w.done().set());
+        self.clock.tasks_cal.write(|w| unsafe { w.bits(1) });
+    }
+
+    fn start_hfxo(&mut self) -> Result<(), BoardError> {
+        self.clock.events_hfclkstarted.reset();
+        self.clock.tasks_hfclkstart.write(|w| unsafe { w.bits(1) });
+        if !self.wait(|clock| clock.events_hfclkstarted.read().bits() == 1, HFXO_TIMEOUT_MS) {
+            self.clock.tasks_hfclkstop.write(|w| unsafe { w.bits(1) });
+            return Err(BoardError::HfxoTimeout);
+        }
+        Ok(())
+    }
+
+    fn start_lfclk(&mut self, source: LfClockSource) -> Result<(), BoardError> {
+        self.clock.events_lfclkstarted.reset();
+        self.clock.lfclksrc.write(|w| match source {
+            LfClockSource::Rc => w.src().rc(),
+            LfClockSource::Crystal => w.src().xtal(),
+            LfClockSource::Synthesized => w.src().synth(),
+        });
+        self.clock.tasks_lfclkstart.write(|w| unsafe { w.bits(1) });
+        if !self.wait(|clock| clock.events_lfclkstarted.read().bits() == 1, LFCLK_TIMEOUT_MS) {
+            return Err(BoardError::LfclkTimeout);
+        }
+        Ok(())
+    }
+
+    fn wait(&self, started: impl Fn(&CLOCK) -> bool, timeout_ms: u32) -> bool {
+        (0..timeout_ms).any(|_| {
+            cortex_m::asm::delay(64_000);
+            started(&self.clock)
+        })
+    }
+}
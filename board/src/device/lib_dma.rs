@@ -5,18 +5,52 @@ pub static RAM: u32 = 0x2000_0000;
 pub static UARTE_TX_BUF_DEF: u32 = RAM;
 pub const UARTE_TX_BUF_MAXLEN: u16 = 4;
 
-pub static UARTE_RX_BUF_DEF: u32 = 0x2000_0040 + 0x01 * UARTE_TX_BUF_MAXLEN as u32;
+// Addresses follow the layout of `DmaBufforBlock`, so regions don't overlap
+pub static UARTE_RX_BUF_DEF: u32 = RAM + core::mem::offset_of!(DmaBufforBlock, uarte_rx) as u32;
 pub const UARTE_RX_BUF_MAXLEN: u8 = 8;
 
-pub static I2C_DATA_BUF: u32 = UARTE_RX_BUF_DEF + 0x01 * UARTE_RX_BUF_MAXLEN as u32;
+pub static I2C_DATA_BUF: u32 = RAM + core::mem::offset_of!(DmaBufforBlock, i2c) as u32;
 pub const I2C_DATA_BUF_LEN: u32 = 512;
 
+// `I2cProxy` copies writes here under the bus lock, apart from the driver's own buffer
+pub static I2C_PROXY_BUF: u32 = RAM + core::mem::offset_of!(DmaBufforBlock, i2c_proxy) as u32;
+pub const I2C_PROXY_BUF_LEN: u32 = I2C_DATA_BUF_LEN;
+
+// I2C slave, register pointer and written data in, register content out
+pub const TWIS_RX_BUF_LEN: usize = 33;
+pub static TWIS_RX_BUF_DEF: u32 = RAM + core::mem::offset_of!(DmaBufforBlock, twis_rx) as u32;
+pub const TWIS_TX_BUF_LEN: usize = 32;
+pub static TWIS_TX_BUF_DEF: u32 = RAM + core::mem::offset_of!(DmaBufforBlock, twis_tx) as u32;
+
+// SPI master, big enough for one BM-Lite link frame
+pub const SPI_BUF_LEN: usize = 264;
+pub static SPI_TX_BUF_DEF: u32 = RAM + core::mem::offset_of!(DmaBufforBlock, spi_tx) as u32;
+pub static SPI_RX_BUF_DEF: u32 = RAM + core::mem::offset_of!(DmaBufforBlock, spi_rx) as u32;
+
+// SPIM3 devices, transfers longer than this go in chunks; TX shares RAM0 with
+// other DMA users, `Spim::transfer` applies the anomaly 198 workaround
+pub const SPIM3_BUF_LEN: usize = 256;
+pub static SPIM3_TX_BUF_DEF: u32 = RAM + core::mem::offset_of!(DmaBufforBlock, spim3_tx) as u32;
+pub static SPIM3_RX_BUF_DEF: u32 = RAM + core::mem::offset_of!(DmaBufforBlock, spim3_rx) as u32;
+
+// PWM sequence, 4 channel values per step in individual load mode
+pub const PWM_SEQ_BUF_LEN: usize = 256;
+pub static PWM_SEQ_BUF_DEF: u32 = RAM + core::mem::offset_of!(DmaBufforBlock, pwm_seq) as u32;
+
 
 #[repr(C)]
 pub struct DmaBufforBlock   {
     pub uarte_tx: RW<[u8; UARTE_TX_BUF_MAXLEN as usize]>,
     pub uarte_rx: RW<[u8; UARTE_RX_BUF_MAXLEN as usize]>,
     pub i2c: RW<[u8; I2C_DATA_BUF_LEN as usize]>,
+    pub i2c_proxy: RW<[u8; I2C_PROXY_BUF_LEN as usize]>,
+    pub pwm_seq: RW<[u16; PWM_SEQ_BUF_LEN]>,
+    pub twis_rx: RW<[u8; TWIS_RX_BUF_LEN]>,
+    pub twis_tx: RW<[u8; TWIS_TX_BUF_LEN]>,
+    pub spi_tx: RW<[u8; SPI_BUF_LEN]>,
+    pub spi_rx: RW<[u8; SPI_BUF_LEN]>,
+    pub spim3_tx: RW<[u8; SPIM3_BUF_LEN]>,
+    pub spim3_rx: RW<[u8; SPIM3_BUF_LEN]>,
 }
 
 pub struct DmaBuffor    {
@@ -31,3 +65,9 @@ impl DmaBuffor  {
     }
 }
 
+impl Default for DmaBuffor  {
+    fn default() -> Self    {
+        Self::new()
+    }
+}
+
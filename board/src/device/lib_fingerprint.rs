@@ -0,0 +1,260 @@
+// Fingerprint workflows on top of `BmLite`
+//
+// Capturing waits for a finger, so it's started by `FingerprintFlow` and
+// finished from the task handling rising edge of the sensor IRQ (GPIOTE
+// channel on `BmLite::irq_pin`). Template storage calls are short and block.
+// The sensor answers one command at a time: nothing else may be sent while a
+// workflow waits for its capture, `cancel` it first.
+
+use crate::device::{Arg, BmLite, BmLiteError, Command, HCP_COMMAND_LEN};
+use crate::hal_main::spim;
+
+/// Default time the sensor waits for a finger
+pub const FINGER_TIMEOUT_MS: u16 = 10_000;
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum FingerprintEvent {
+    /// Finger captured in wait mode
+    FingerDetected,
+    /// Touch accepted, `remaining` more are needed for template `id`
+    EnrollProgress { id: u16, remaining: u8 },
+    /// Template stored under id
+    Enrolled(u16),
+    /// Finger matches stored template
+    Identified(u16),
+    NoMatch,
+    /// Template removed, `None` for all of them
+    Deleted(Option<u16>),
+    /// Number of stored templates
+    Templates(u16),
+    /// No template id is left for enrolling
+    StorageFull,
+    Failed(BmLiteError),
+}
+
+impl FingerprintEvent {
+    /// One line for UART or OLED
+    pub fn write_message<W: core::fmt::Write>(&self, out: &mut W) -> core::fmt::Result {
+        match self {
+            FingerprintEvent::FingerDetected => write!(out, "Finger detected"),
+            FingerprintEvent::EnrollProgress { id, remaining } =>
+                write!(out, "Enroll {}: {} more", id, remaining),
+            FingerprintEvent::Enrolled(id) => write!(out, "Enrolled as {}", id),
+            FingerprintEvent::Identified(id) => write!(out, "Match: {}", id),
+            FingerprintEvent::NoMatch => write!(out, "No match"),
+            FingerprintEvent::Deleted(Some(id)) => write!(out, "Deleted {}", id),
+            FingerprintEvent::Deleted(None) => write!(out, "Deleted all"),
+            FingerprintEvent::Templates(count) => write!(out, "{} template(s)", count),
+            FingerprintEvent::StorageFull => write!(out, "Template storage full"),
+            FingerprintEvent::Failed(BmLiteError::Result(code)) => write!(out, "Sensor error {}", code),
+            FingerprintEvent::Failed(error) => write!(out, "Error: {:?}", error),
+        }
+    }
+}
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum FlowState {
+    Idle,
+    WaitFinger,
+    Enrolling(u16),
+    Identifying,
+}
+
+
+/// Capture based workflows, one at a time
+pub struct FingerprintFlow {
+    state: FlowState,
+    timeout_ms: u16,
+}
+
+impl FingerprintFlow {
+    pub fn new() -> Self {
+        FingerprintFlow { state: FlowState::Idle, timeout_ms: FINGER_TIMEOUT_MS }
+    }
+
+    pub fn state(&self) -> FlowState {
+        self.state
+    }
+
+    pub fn set_timeout(&mut self, timeout_ms: u16) {
+        self.timeout_ms = timeout_ms;
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.state == FlowState::Idle
+    }
+
+    /// Report `FingerDetected` on the next touch
+    pub fn wait_finger<T: spim::Instance>(&mut self, sensor: &mut BmLite<T>) -> Result<(), BmLiteError> {
+        self.check_idle()?;
+        self.capture(sensor)?;
+        self.state = FlowState::WaitFinger;
+        Ok(())
+    }
+
+    /// Collect touches for a new template saved as `id`
+    pub fn enroll<T: spim::Instance>(&mut self, sensor: &mut BmLite<T>, id: u16) -> Result<(), BmLiteError> {
+        self.check_idle()?;
+        let mut reply = [0u8; HCP_COMMAND_LEN];
+        sensor.call(Command::Enroll, &[(Arg::Start, &[])], &mut reply)?;
+        self.capture(sensor)?;
+        self.state = FlowState::Enrolling(id);
+        Ok(())
+    }
+
+    /// Match the next touch against stored templates
+    pub fn identify<T: spim::Instance>(&mut self, sensor: &mut BmLite<T>) -> Result<(), BmLiteError> {
+        self.check_idle()?;
+        self.capture(sensor)?;
+        self.state = FlowState::Identifying;
+        Ok(())
+    }
+
+    /// Forget the running workflow, the sensor is reset to drop its capture
+    pub fn cancel<T: spim::Instance>(&mut self, sensor: &mut BmLite<T>) {
+        if self.state != FlowState::Idle {
+            sensor.reset();
+            self.state = FlowState::Idle;
+        }
+    }
+
+    /// Call on rising edge of the sensor IRQ
+    pub fn on_irq<T: spim::Instance>(&mut self, sensor: &mut BmLite<T>) -> Option<FingerprintEvent> {
+        // Edges caused by ACKs of our own commands are gone by now
+        if self.state == FlowState::Idle || !sensor.is_irq() {
+            return None;
+        }
+
+        let state = core::mem::replace(&mut self.state, FlowState::Idle);
+        let event = self.advance(sensor, state).unwrap_or_else(FingerprintEvent::Failed);
+        Some(event)
+    }
+
+    fn advance<T: spim::Instance>(&mut self, sensor: &mut BmLite<T>, state: FlowState)
+        -> Result<FingerprintEvent, BmLiteError>
+    {
+        let mut reply = [0u8; HCP_COMMAND_LEN];
+        sensor.finish(&mut reply)?;
+
+        match state {
+            FlowState::Idle | FlowState::WaitFinger => Ok(FingerprintEvent::FingerDetected),
+            FlowState::Enrolling(id) => {
+                let added = sensor.call(Command::Enroll, &[(Arg::Add, &[])], &mut reply)?;
+                let remaining = added.arg(Arg::Count).and_then(|count| count.first().copied()).unwrap_or(0);
+                if remaining > 0 {
+                    self.capture(sensor)?;
+                    self.state = FlowState::Enrolling(id);
+                    return Ok(FingerprintEvent::EnrollProgress { id, remaining });
+                }
+                sensor.call(Command::Enroll, &[(Arg::Finish, &[])], &mut reply)?;
+                sensor.call(Command::Template, &[(Arg::Save, &[]), (Arg::Id, &id.to_le_bytes())], &mut reply)?;
+                Ok(FingerprintEvent::Enrolled(id))
+            },
+            FlowState::Identifying => {
+                sensor.call(Command::Image, &[(Arg::Extract, &[])], &mut reply)?;
+                let answer = sensor.call(Command::Identify, &[], &mut reply)?;
+                let matched = answer.arg(Arg::Match).and_then(|data| data.first().copied()).unwrap_or(0);
+                match answer.arg_u16(Arg::Id) {
+                    Some(id) if matched != 0 => Ok(FingerprintEvent::Identified(id)),
+                    _ => Ok(FingerprintEvent::NoMatch),
+                }
+            },
+        }
+    }
+
+    // A reply to the running capture would be taken for the new command's
+    fn check_idle(&self) -> Result<(), BmLiteError> {
+        if self.is_idle() { Ok(()) } else { Err(BmLiteError::Busy) }
+    }
+
+    fn capture<T: spim::Instance>(&mut self, sensor: &mut BmLite<T>) -> Result<(), BmLiteError> {
+        sensor.start(Command::Capture, &[(Arg::Timeout, &self.timeout_ms.to_le_bytes())])
+    }
+}
+
+impl Default for FingerprintFlow {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+
+impl<T> BmLite<T>
+where
+    T: spim::Instance,
+{
+    /// Remove template `id`, or all of them for `None`
+    pub fn delete_template(&mut self, id: Option<u16>) -> FingerprintEvent {
+        let mut reply = [0u8; HCP_COMMAND_LEN];
+        let id_bytes = id.unwrap_or(0).to_le_bytes();
+        let result = match id {
+            Some(_) => self.call(Command::StorageTemplate, &[(Arg::Delete, &[]), (Arg::Id, &id_bytes)], &mut reply),
+            None => self.call(Command::StorageTemplate, &[(Arg::Delete, &[]), (Arg::All, &[])], &mut reply),
+        };
+        match result {
+            Ok(_) => FingerprintEvent::Deleted(id),
+            Err(error) => FingerprintEvent::Failed(error),
+        }
+    }
+
+    /// Ids of stored templates go to `ids`, the event carries how many there are
+    pub fn template_ids(&mut self, ids: &mut [u16]) -> FingerprintEvent {
+        let mut reply = [0u8; HCP_COMMAND_LEN];
+        let answer = match self.call(Command::StorageTemplate, &[(Arg::Id, &[])], &mut reply) {
+            Ok(answer) => answer,
+            Err(error) => return FingerprintEvent::Failed(error),
+        };
+
+        let data = answer.arg(Arg::Data).unwrap_or(&[]);
+        for (slot, id) in ids.iter_mut().zip(stored_ids(data)) {
+            *slot = id;
+        }
+        FingerprintEvent::Templates((data.len() / 2) as u16)
+    }
+
+    /// Highest stored template id, `None` with storage empty
+    pub fn highest_template_id(&mut self) -> Result<Option<u16>, BmLiteError> {
+        let mut reply = [0u8; HCP_COMMAND_LEN];
+        let answer = self.call(Command::StorageTemplate, &[(Arg::Id, &[])], &mut reply)?;
+        Ok(stored_ids(answer.arg(Arg::Data).unwrap_or(&[])).max())
+    }
+}
+
+// Ids in `Arg::Data` of a template list, little endian u16 each
+fn stored_ids(data: &[u8]) -> impl Iterator<Item = u16> + '_ {
+    data.chunks_exact(2).map(|id| u16::from_le_bytes([id[0], id[1]]))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stored_ids_reads_every_pair() {
+        let data = [0x01, 0x00, 0x34, 0x12, 0xFF];
+        let mut ids = stored_ids(&data);
+        assert_eq!(ids.next(), Some(1));
+        assert_eq!(ids.next(), Some(0x1234));
+        assert_eq!(ids.next(), None);
+    }
+
+    #[test]
+    fn highest_id_looks_past_any_buffer() {
+        let mut data = [0u8; 2 * 40];
+        for (index, id) in data.chunks_exact_mut(2).enumerate() {
+            id.copy_from_slice(&(index as u16).to_le_bytes());
+        }
+        assert_eq!(stored_ids(&data).max(), Some(39));
+        assert_eq!(stored_ids(&[]).max(), None);
+    }
+
+    #[test]
+    fn new_flow_is_idle() {
+        let flow = FingerprintFlow::new();
+        assert!(flow.is_idle());
+        assert_eq!(flow.check_idle(), Ok(()));
+    }
+}
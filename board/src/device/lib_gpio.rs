@@ -1,48 +1,74 @@
 use crate::hal_main as hal;
 pub use hal::{gpio, gpio::*};
+use crate::device::{ButtonPipeline, GpioteError, GpioteManager, PortEventSense};
 
 use embedded_hal::digital::v2::
     {OutputPin as _, InputPin as _,
         StatefulOutputPin};
 
-        
+
+// Pins come from the board profile, see `BoardBuilder`
 pub struct Leds {
-    // LED1: pin P0.13, green
+    // LED1: DK P0.13, Dongle P0.06
     pub _1: Led,
-    // LED2: pin P0.14, green
+    // LED2: DK P0.14, Dongle P0.08 (red)
     pub _2: Led,
-    // LED3: pin P0.15, green
+    // LED3: DK P0.15, Dongle P1.09 (green)
     pub _3: Led,
-    // LED4: pin P0.16, green
+    // LED4: DK P0.16, Dongle P0.12 (blue)
     pub _4: Led,
 }
 
+/// Pin level that lights the LED
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum LedPolarity {
+    ActiveLow,
+    ActiveHigh,
+}
+
+impl LedPolarity {
+    /// Level to configure the pin with, so LED starts dark
+    pub fn off_level(self) -> Level {
+        match self {
+            LedPolarity::ActiveLow => Level::High,
+            LedPolarity::ActiveHigh => Level::Low,
+        }
+    }
+}
+
 pub struct Led  {
-    pub inner: Pin<Output<PushPull>>
+    pub inner: Pin<Output<PushPull>>,
+    pub polarity: LedPolarity,
 }
 
 impl Led    {
     /// Turns on LED 
     pub fn on(&mut self)    {
-        let _ = self.inner.set_low();
+        let _ = match self.polarity {
+            LedPolarity::ActiveLow => self.inner.set_low(),
+            LedPolarity::ActiveHigh => self.inner.set_high(),
+        };
     }
 
     /// Turns off LED 
     pub fn off(&mut self)    {    
-        let _ = self.inner.set_high();
+        let _ = match self.polarity {
+            LedPolarity::ActiveLow => self.inner.set_high(),
+            LedPolarity::ActiveHigh => self.inner.set_low(),
+        };
     }
 
     pub fn toggle(&mut self)    {
         if self.is_on() {
-            let _ = self.off();
+            self.off();
         } else {
-            let _ = self.on();
+            self.on();
         }
     }
 
     /// Returns `true` if the LED is in the OFF state
     pub fn is_off(&self) -> bool {
-        self.inner.is_set_high() == Ok(true)
+        self.inner.is_set_high() == Ok(self.polarity == LedPolarity::ActiveLow)
     }
     
     /// Returns `true` if the LED is in the ON state
@@ -51,17 +77,52 @@ impl Led    {
     }
 }
 
+// Pins come from the board profile, see `BoardBuilder`
 pub struct Buttons {
-        // Button1: pin P0.11, green
-        pub _1: Button,
-        // Button2: pin P0.12, green
-        pub _2: Button,
-        // Button3: pin P0.24, green
-        pub _3: Button,
-        // Button4: pin P0.25, green
-        pub _4: Button,
+    // Button1: DK P0.11, Dongle P1.06 (SW1)
+    pub _1: Button,
+    // Button2: DK P0.12, Dongle P1.00
+    pub _2: Button,
+    // Button3: DK P0.24, Dongle P0.24
+    pub _3: Button,
+    // Button4: DK P0.25, Dongle P0.22
+    pub _4: Button,
+}
+
+impl Buttons {
+    /// Bit mask of pushed buttons, bit 0 is button 1
+    pub fn pressed_mask(&self) -> u8 {
+        [&self._1, &self._2, &self._3, &self._4].iter()
+            .enumerate()
+            .fold(0, |mask, (i, button)| if button.is_pushed() { mask | 1 << i } else { mask })
+    }
+
+    /// Body of the sample task: feed the pressed mask to `pipeline` and arm the
+    /// PORT event from it; returns `true` when events may be waiting
+    pub fn debounce<T: Copy + PartialEq>(&self, pipeline: &mut ButtonPipeline,
+        gpiote: &mut GpioteManager<T>, now: u32, schedule: impl FnOnce(u32) -> bool) -> bool
+    {
+        let mask = self.pressed_mask();
+        let changed = pipeline.sample(now, mask, schedule);
+        // Wait for the opposite of the sampled level, so release is seen too
+        self.arm_port_event(gpiote, mask).ok();
+        changed
+    }
+
+    /// Sense the opposite of the levels in `mask` (the one just sampled), so any
+    /// change since the sample raises PORT event again
+    pub fn arm_port_event<T: Copy + PartialEq>(&self, gpiote: &mut GpioteManager<T>, mask: u8)
+        -> Result<(), GpioteError>
+    {
+        for (i, button) in [&self._1, &self._2, &self._3, &self._4].iter().enumerate() {
+            let sense = if mask & 1 << i != 0 { PortEventSense::High } else { PortEventSense::Low };
+            gpiote.port_sense(&button.inner, sense)?;
+        }
+        Ok(())
+    }
 }
 
+/// Push button to GND, the pin pulls up
 pub struct Button   {
     pub inner: Pin<Input<PullUp>>
 }
@@ -1,2 +1,329 @@
 use crate::hal_main as hal;
-pub use hal::gpiote::{self, *};
\ No newline at end of file
+pub use hal::gpiote::{self, *};
+use hal::gpio::Port;
+use hal::pac::GPIOTE;
+
+const GPIOTE_CHANNELS: usize = 8;
+const PORT_SLOTS: usize = 16;
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum GpioteError {
+    /// All 8 channels are taken
+    NoFreeChannel,
+    /// Too many pins registered on PORT event
+    NoFreePortSlot,
+    /// Channel number out of range or not allocated
+    InvalidChannel,
+    /// Pin already has a channel or a PORT slot
+    PinInUse,
+    /// Pin has no PORT slot to release
+    PinNotRegistered,
+}
+
+
+/// Hands out GPIOTE channels and PORT event pins, `T` is the application tag
+/// (usually an enum naming the task to spawn) reported by `dispatch`
+pub struct GpioteManager<T: Copy + PartialEq> {
+    gpiote: Gpiote,
+    slots: Slots<T>,
+}
+
+impl<T> GpioteManager<T>
+where
+    T: Copy + PartialEq,
+{
+    pub fn new(gpiote: Gpiote) -> Self {
+        GpioteManager { gpiote, slots: Slots::new() }
+    }
+
+    /// Inner GPIOTE
+    pub fn gpiote(&self) -> &Gpiote {
+        &self.gpiote
+    }
+
+    /// Take the first free channel for `pin` and enable its interrupt,
+    /// returns the channel number
+    pub fn channel<P: GpioteInputPin>(&mut self, pin: &P, edge: EventPolarity, tag: T)
+        -> Result<usize, GpioteError>
+    {
+        let number = self.slots.take_channel(tag, pin_id(pin))?;
+
+        let channel = self.channel_n(number);
+        let event = channel.input_pin(pin);
+        match edge {
+            EventPolarity::HiToLo => event.hi_to_lo(),
+            EventPolarity::LoToHi => event.lo_to_hi(),
+            EventPolarity::Toggle => event.toggle(),
+            EventPolarity::None => event.none(),
+        };
+        event.enable_interrupt();
+        channel.reset_events();
+        Ok(number)
+    }
+
+    /// Give the channel back, its event and interrupt are switched off
+    pub fn release_channel(&mut self, number: usize) -> Result<(), GpioteError> {
+        self.slots.release_channel(number)?;
+
+        // Hal has no way to unconfigure a channel
+        let gpiote = unsafe { &*GPIOTE::ptr() };
+        gpiote.intenclr.write(|w| unsafe { w.bits(1 << number) });
+        gpiote.config[number].reset();
+        gpiote.events_in[number].write(|w| w);
+        Ok(())
+    }
+
+    /// Let `pin` raise the shared PORT event at `sense` level
+    ///
+    /// PORT event doesn't tell which pin caused it, so all port tags are
+    /// reported together; register related pins (e.g. buttons) with one tag.
+    pub fn port<P: GpioteInputPin>(&mut self, pin: &P, sense: PortEventSense, tag: T)
+        -> Result<(), GpioteError>
+    {
+        self.slots.take_port(tag, pin_id(pin))?;
+        self.sense(pin, sense);
+        self.gpiote.port().enable_interrupt();
+        Ok(())
+    }
+
+    /// Change the level a pin registered with `port` senses
+    pub fn port_sense<P: GpioteInputPin>(&mut self, pin: &P, sense: PortEventSense)
+        -> Result<(), GpioteError>
+    {
+        if !self.slots.has_port(pin_id(pin)) {
+            return Err(GpioteError::PinNotRegistered);
+        }
+        self.sense(pin, sense);
+        Ok(())
+    }
+
+    /// Give the PORT slot of `pin` back, the pin stops sensing; PORT interrupt
+    /// is switched off with the last slot
+    pub fn release_port<P: GpioteInputPin>(&mut self, pin: &P) -> Result<(), GpioteError> {
+        let last = self.slots.release_port(pin_id(pin))?;
+
+        let port = self.gpiote.port();
+        port.input_pin(pin).disabled();
+        if last {
+            port.disable_interrupt();
+            port.reset_events();
+        }
+        Ok(())
+    }
+
+    /// Check and clear all events, returns tags of registrations which fired
+    pub fn dispatch(&mut self) -> Fired<T> {
+        let mut fired = Fired::new();
+
+        for (number, slot) in self.slots.channels.iter().enumerate() {
+            if let Some((tag, _)) = slot {
+                let channel = self.channel_n(number);
+                if channel.is_event_triggered() {
+                    channel.reset_events();
+                    fired.push(*tag);
+                }
+            }
+        }
+
+        let port = self.gpiote.port();
+        if port.is_event_triggered() {
+            port.reset_events();
+            for (tag, _) in self.slots.port.iter().flatten() {
+                fired.push(*tag);
+            }
+        }
+
+        fired
+    }
+
+    fn sense<P: GpioteInputPin>(&self, pin: &P, sense: PortEventSense) {
+        let port = self.gpiote.port();
+        let event = port.input_pin(pin);
+        match sense {
+            PortEventSense::Low => event.low(),
+            PortEventSense::High => event.high(),
+            PortEventSense::Disabled => event.disabled(),
+        }
+    }
+
+    fn channel_n(&self, number: usize) -> GpioteChannel<'_> {
+        match number {
+            0 => self.gpiote.channel0(),
+            1 => self.gpiote.channel1(),
+            2 => self.gpiote.channel2(),
+            3 => self.gpiote.channel3(),
+            4 => self.gpiote.channel4(),
+            5 => self.gpiote.channel5(),
+            6 => self.gpiote.channel6(),
+            _ => self.gpiote.channel7(),
+        }
+    }
+}
+
+
+// Tag and pin (`port * 32 + pin`) of each registration, no registers inside
+struct Slots<T: Copy + PartialEq> {
+    channels: [Option<(T, u8)>; GPIOTE_CHANNELS],
+    port: [Option<(T, u8)>; PORT_SLOTS],
+}
+
+impl<T: Copy + PartialEq> Slots<T> {
+    fn new() -> Self {
+        Slots { channels: [None; GPIOTE_CHANNELS], port: [None; PORT_SLOTS] }
+    }
+
+    fn take_channel(&mut self, tag: T, id: u8) -> Result<usize, GpioteError> {
+        if self.is_registered(id) {
+            return Err(GpioteError::PinInUse);
+        }
+        let number = self.channels.iter()
+            .position(|slot| slot.is_none())
+            .ok_or(GpioteError::NoFreeChannel)?;
+        self.channels[number] = Some((tag, id));
+        Ok(number)
+    }
+
+    fn release_channel(&mut self, number: usize) -> Result<(), GpioteError> {
+        match self.channels.get_mut(number) {
+            Some(slot @ Some(_)) => {
+                *slot = None;
+                Ok(())
+            },
+            _ => Err(GpioteError::InvalidChannel),
+        }
+    }
+
+    fn take_port(&mut self, tag: T, id: u8) -> Result<(), GpioteError> {
+        if self.is_registered(id) {
+            return Err(GpioteError::PinInUse);
+        }
+        let slot = self.port.iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(GpioteError::NoFreePortSlot)?;
+        *slot = Some((tag, id));
+        Ok(())
+    }
+
+    fn has_port(&self, id: u8) -> bool {
+        self.port.iter().flatten().any(|(_, registered)| *registered == id)
+    }
+
+    // Returns `true` when no PORT slot is left taken
+    fn release_port(&mut self, id: u8) -> Result<bool, GpioteError> {
+        let slot = self.port.iter_mut()
+            .find(|slot| matches!(slot, Some((_, registered)) if *registered == id))
+            .ok_or(GpioteError::PinNotRegistered)?;
+        *slot = None;
+        Ok(self.port.iter().all(|slot| slot.is_none()))
+    }
+
+    fn is_registered(&self, id: u8) -> bool {
+        self.channels.iter().chain(self.port.iter())
+            .flatten()
+            .any(|(_, registered)| *registered == id)
+    }
+}
+
+fn pin_id<P: GpioteInputPin>(pin: &P) -> u8 {
+    match pin.port() {
+        Port::Port0 => pin.pin(),
+        Port::Port1 => 32 + pin.pin(),
+    }
+}
+
+
+/// Tags reported by one `dispatch`, each at most once
+pub struct Fired<T: Copy + PartialEq> {
+    tags: [Option<T>; GPIOTE_CHANNELS + PORT_SLOTS],
+    len: usize,
+    next: usize,
+}
+
+impl<T: Copy + PartialEq> Fired<T> {
+    fn new() -> Self {
+        Fired { tags: [None; GPIOTE_CHANNELS + PORT_SLOTS], len: 0, next: 0 }
+    }
+
+    fn push(&mut self, tag: T) {
+        if self.tags[..self.len].contains(&Some(tag)) || self.len == self.tags.len() {
+            return;
+        }
+        self.tags[self.len] = Some(tag);
+        self.len += 1;
+    }
+}
+
+impl<T: Copy + PartialEq> Iterator for Fired<T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        if self.next == self.len {
+            return None;
+        }
+        self.next += 1;
+        self.tags[self.next - 1]
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn channels_run_out_and_come_back() {
+        let mut slots = Slots::new();
+        for id in 0..GPIOTE_CHANNELS as u8 {
+            assert_eq!(slots.take_channel('a', id), Ok(id as usize));
+        }
+        assert_eq!(slots.take_channel('a', 40), Err(GpioteError::NoFreeChannel));
+
+        assert_eq!(slots.release_channel(3), Ok(()));
+        assert_eq!(slots.release_channel(3), Err(GpioteError::InvalidChannel));
+        assert_eq!(slots.release_channel(GPIOTE_CHANNELS), Err(GpioteError::InvalidChannel));
+        assert_eq!(slots.take_channel('b', 40), Ok(3));
+    }
+
+    #[test]
+    fn pin_registers_once() {
+        let mut slots = Slots::new();
+        slots.take_port('a', 11).unwrap();
+        assert_eq!(slots.take_port('a', 11), Err(GpioteError::PinInUse));
+        assert_eq!(slots.take_channel('b', 11), Err(GpioteError::PinInUse));
+
+        slots.take_channel('b', 12).unwrap();
+        assert_eq!(slots.take_port('a', 12), Err(GpioteError::PinInUse));
+    }
+
+    #[test]
+    fn port_slots_run_out() {
+        let mut slots = Slots::new();
+        for id in 0..PORT_SLOTS as u8 {
+            slots.take_port('a', id).unwrap();
+        }
+        assert_eq!(slots.take_port('a', 40), Err(GpioteError::NoFreePortSlot));
+    }
+
+    #[test]
+    fn last_port_release_is_reported() {
+        let mut slots = Slots::new();
+        slots.take_port('a', 11).unwrap();
+        slots.take_port('a', 12).unwrap();
+        assert!(slots.has_port(11));
+
+        assert_eq!(slots.release_port(11), Ok(false));
+        assert!(!slots.has_port(11));
+        assert_eq!(slots.release_port(11), Err(GpioteError::PinNotRegistered));
+        assert_eq!(slots.release_port(12), Ok(true));
+    }
+
+    #[test]
+    fn fired_reports_each_tag_once() {
+        let mut fired = Fired::new();
+        fired.push('a');
+        fired.push('b');
+        fired.push('a');
+        assert_eq!(fired.collect::<Vec<_>>(), ['a', 'b']);
+    }
+}
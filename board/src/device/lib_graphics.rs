@@ -0,0 +1,509 @@
+// 1-bpp framebuffer for the SSD1306
+//
+// Layout matches the display RAM (see `FRAMEBUFFER_LEN`), so flushing is a
+// plain copy. A copy of what the display shows is kept, `flush` compares
+// against it and sends only the pages and column span that differ, so a
+// screen cleared and redrawn every frame costs only what really changed.
+// Coordinates are i32 and clipped, so shapes may stick out of the screen.
+
+use core::convert::Infallible;
+use core::fmt;
+use embedded_graphics_core::{
+    draw_target::DrawTarget,
+    geometry::{OriginDimensions, Size},
+    pixelcolor::BinaryColor,
+    Pixel,
+};
+use embedded_hal::blocking::i2c::Write;
+
+use crate::device::{Ssd1306, DISPLAY_HEIGHT, DISPLAY_PAGES, DISPLAY_WIDTH, FRAMEBUFFER_LEN};
+
+pub const FONT_WIDTH: i32 = 5;
+pub const FONT_HEIGHT: i32 = 7;
+/// Horizontal and vertical text advance, glyph plus one pixel of spacing
+pub const CHAR_WIDTH: i32 = FONT_WIDTH + 1;
+pub const LINE_HEIGHT: i32 = FONT_HEIGHT + 1;
+
+
+pub struct Framebuffer {
+    buffer: [u8; FRAMEBUFFER_LEN],
+    // Display RAM content after the last flush
+    shown: [u8; FRAMEBUFFER_LEN],
+    // Display content unknown, next flush sends everything
+    stale: bool,
+}
+
+impl Framebuffer {
+    pub fn new() -> Self {
+        Framebuffer {
+            buffer: [0; FRAMEBUFFER_LEN],
+            shown: [0; FRAMEBUFFER_LEN],
+            stale: false,
+        }
+    }
+
+    /// Raw display RAM image
+    pub fn as_bytes(&self) -> &[u8; FRAMEBUFFER_LEN] {
+        &self.buffer
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        self.dirty().0 != 0
+    }
+
+    /// Force full flush, e.g. after the display was cleared or re-initialized
+    pub fn mark_all_dirty(&mut self) {
+        self.stale = true;
+    }
+
+    /// Send changed pages to the display
+    pub fn flush<I: Write>(&mut self, display: &mut Ssd1306, i2c: &mut I) -> Result<(), I::Error> {
+        let (pages, columns) = self.dirty();
+        let mut page = 0;
+        while page < DISPLAY_PAGES {
+            if pages & (1 << page) == 0 {
+                page += 1;
+                continue;
+            }
+            // Neighbouring dirty pages go out in one window
+            let first = page;
+            while page < DISPLAY_PAGES && pages & (1 << page) != 0 {
+                page += 1;
+            }
+            display.flush_region(i2c, &self.buffer, first..page, columns.clone())?;
+        }
+
+        self.shown = self.buffer;
+        self.stale = false;
+        Ok(())
+    }
+
+    // Pages (bit n is page n) and column span differing from the display
+    fn dirty(&self) -> (u8, core::ops::Range<usize>) {
+        if self.stale {
+            return (0xFF, 0..DISPLAY_WIDTH);
+        }
+        let (mut pages, mut first, mut last) = (0u8, DISPLAY_WIDTH, 0);
+        for (i, (new, old)) in self.buffer.iter().zip(self.shown.iter()).enumerate() {
+            if new != old {
+                let column = i % DISPLAY_WIDTH;
+                pages |= 1 << (i / DISPLAY_WIDTH);
+                first = first.min(column);
+                last = last.max(column);
+            }
+        }
+        (pages, first..last + 1)
+    }
+
+    pub fn clear(&mut self) {
+        self.fill(false);
+    }
+
+    pub fn fill(&mut self, on: bool) {
+        self.buffer = [if on { 0xFF } else { 0x00 }; FRAMEBUFFER_LEN];
+    }
+
+    pub fn pixel(&self, x: i32, y: i32) -> bool {
+        match index(x, y) {
+            Some((i, bit)) => self.buffer[i] & bit != 0,
+            None => false,
+        }
+    }
+
+    pub fn set_pixel(&mut self, x: i32, y: i32, on: bool) {
+        let (i, bit) = match index(x, y) {
+            Some(position) => position,
+            None => return,
+        };
+        if on {
+            self.buffer[i] |= bit;
+        } else {
+            self.buffer[i] &= !bit;
+        }
+    }
+
+    /// Bresenham line, both ends included
+    pub fn line(&mut self, x0: i32, y0: i32, x1: i32, y1: i32, on: bool) {
+        let (dx, dy) = ((x1 - x0).abs(), -(y1 - y0).abs());
+        let (sx, sy) = (if x0 < x1 { 1 } else { -1 }, if y0 < y1 { 1 } else { -1 });
+        let (mut x, mut y, mut error) = (x0, y0, dx + dy);
+
+        loop {
+            self.set_pixel(x, y, on);
+            if x == x1 && y == y1 {
+                break;
+            }
+            let double = 2 * error;
+            if double >= dy {
+                error += dy;
+                x += sx;
+            }
+            if double <= dx {
+                error += dx;
+                y += sy;
+            }
+        }
+    }
+
+    /// Outline of `width` x `height` rectangle with top left corner at `x`, `y`
+    pub fn rect(&mut self, x: i32, y: i32, width: i32, height: i32, on: bool) {
+        if width <= 0 || height <= 0 {
+            return;
+        }
+        let (right, bottom) = (x + width - 1, y + height - 1);
+        self.line(x, y, right, y, on);
+        self.line(x, bottom, right, bottom, on);
+        self.line(x, y, x, bottom, on);
+        self.line(right, y, right, bottom, on);
+    }
+
+    pub fn fill_rect(&mut self, x: i32, y: i32, width: i32, height: i32, on: bool) {
+        for row in y.max(0)..(y + height).min(DISPLAY_HEIGHT as i32) {
+            for column in x.max(0)..(x + width).min(DISPLAY_WIDTH as i32) {
+                self.set_pixel(column, row, on);
+            }
+        }
+    }
+
+    /// Midpoint circle outline
+    pub fn circle(&mut self, cx: i32, cy: i32, radius: i32, on: bool) {
+        self.circle_points(radius, |fb, x, y| {
+            for (px, py) in [(x, y), (y, x), (-y, x), (-x, y), (-x, -y), (-y, -x), (y, -x), (x, -y)] {
+                fb.set_pixel(cx + px, cy + py, on);
+            }
+        });
+    }
+
+    pub fn fill_circle(&mut self, cx: i32, cy: i32, radius: i32, on: bool) {
+        self.circle_points(radius, |fb, x, y| {
+            fb.line(cx - x, cy + y, cx + x, cy + y, on);
+            fb.line(cx - x, cy - y, cx + x, cy - y, on);
+            fb.line(cx - y, cy + x, cx + y, cy + x, on);
+            fb.line(cx - y, cy - x, cx + y, cy - x, on);
+        });
+    }
+
+    /// Blit `width` x `height` bitmap, rows top to bottom, MSB first,
+    /// each row padded to whole bytes; only set bits are drawn
+    pub fn bitmap(&mut self, x: i32, y: i32, width: i32, height: i32, data: &[u8], on: bool) {
+        let stride = (width as usize).div_ceil(8);
+        for row in 0..height {
+            for column in 0..width {
+                let byte = data.get(row as usize * stride + column as usize / 8).copied().unwrap_or(0);
+                if byte & (0x80 >> (column % 8)) != 0 {
+                    self.set_pixel(x + column, y + row, on);
+                }
+            }
+        }
+    }
+
+    /// Draw text in the built-in 5x7 font, `\n` starts a new line at `x`;
+    /// returns position after the last character
+    pub fn text(&mut self, x: i32, y: i32, text: &str, on: bool) -> (i32, i32) {
+        let (mut cursor_x, mut cursor_y) = (x, y);
+        for sign in text.chars() {
+            if sign == '\n' {
+                cursor_x = x;
+                cursor_y += LINE_HEIGHT;
+                continue;
+            }
+            for (column, bits) in glyph(sign).iter().enumerate() {
+                for row in 0..FONT_HEIGHT {
+                    if bits & (1 << row) != 0 {
+                        self.set_pixel(cursor_x + column as i32, cursor_y + row, on);
+                    }
+                }
+            }
+            cursor_x += CHAR_WIDTH;
+        }
+        (cursor_x, cursor_y)
+    }
+
+    /// Dump as plain PBM (P1) image, lit pixels are written as 1 (black)
+    pub fn write_pbm<W: fmt::Write>(&self, out: &mut W) -> fmt::Result {
+        writeln!(out, "P1")?;
+        writeln!(out, "{} {}", DISPLAY_WIDTH, DISPLAY_HEIGHT)?;
+        for y in 0..DISPLAY_HEIGHT as i32 {
+            for x in 0..DISPLAY_WIDTH as i32 {
+                out.write_char(if self.pixel(x, y) { '1' } else { '0' })?;
+            }
+            out.write_char('\n')?;
+        }
+        Ok(())
+    }
+
+    // Calls `plot` with one octant of the circle, caller mirrors it
+    fn circle_points<F: FnMut(&mut Self, i32, i32)>(&mut self, radius: i32, mut plot: F) {
+        if radius < 0 {
+            return;
+        }
+        let (mut x, mut y, mut error) = (radius, 0, 1 - radius);
+        while x >= y {
+            plot(self, x, y);
+            y += 1;
+            if error < 0 {
+                error += 2 * y + 1;
+            } else {
+                x -= 1;
+                error += 2 * (y - x) + 1;
+            }
+        }
+    }
+}
+
+impl Default for Framebuffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl OriginDimensions for Framebuffer {
+    fn size(&self) -> Size {
+        Size::new(DISPLAY_WIDTH as u32, DISPLAY_HEIGHT as u32)
+    }
+}
+
+impl DrawTarget for Framebuffer {
+    type Color = BinaryColor;
+    type Error = Infallible;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Self::Color>>,
+    {
+        for Pixel(point, color) in pixels {
+            self.set_pixel(point.x, point.y, color.is_on());
+        }
+        Ok(())
+    }
+}
+
+// Byte index and bit mask of a pixel, `None` outside of the screen
+fn index(x: i32, y: i32) -> Option<(usize, u8)> {
+    if !(0..DISPLAY_WIDTH as i32).contains(&x) || !(0..DISPLAY_HEIGHT as i32).contains(&y) {
+        return None;
+    }
+    let (x, y) = (x as usize, y as usize);
+    Some(((y / 8) * DISPLAY_WIDTH + x, 1 << (y % 8)))
+}
+
+
+fn glyph(sign: char) -> &'static [u8; FONT_WIDTH as usize] {
+    let code = sign as u32;
+    if (0x20..=0x7E).contains(&code) {
+        &FONT_5X7[(code - 0x20) as usize]
+    } else {
+        &FONT_5X7[(b'?' - 0x20) as usize]
+    }
+}
+
+// Printable ASCII 0x20..=0x7E, one byte per column, bit 0 is the top row
+const FONT_5X7: [[u8; FONT_WIDTH as usize]; 95] = [
+    [0x00, 0x00, 0x00, 0x00, 0x00], // ' '
+    [0x00, 0x00, 0x5F, 0x00, 0x00], // !
+    [0x00, 0x07, 0x00, 0x07, 0x00], // "
+    [0x14, 0x7F, 0x14, 0x7F, 0x14], // #
+    [0x24, 0x2A, 0x7F, 0x2A, 0x12], // $
+    [0x23, 0x13, 0x08, 0x64, 0x62], // %
+    [0x36, 0x49, 0x55, 0x22, 0x50], // &
+    [0x00, 0x05, 0x03, 0x00, 0x00], // '
+    [0x00, 0x1C, 0x22, 0x41, 0x00], // (
+    [0x00, 0x41, 0x22, 0x1C, 0x00], // )
+    [0x08, 0x2A, 0x1C, 0x2A, 0x08], // *
+    [0x08, 0x08, 0x3E, 0x08, 0x08], // +
+    [0x00, 0x50, 0x30, 0x00, 0x00], // ,
+    [0x08, 0x08, 0x08, 0x08, 0x08], // -
+    [0x00, 0x60, 0x60, 0x00, 0x00], // .
+    [0x20, 0x10, 0x08, 0x04, 0x02], // /
+    [0x3E, 0x51, 0x49, 0x45, 0x3E], // 0
+    [0x00, 0x42, 0x7F, 0x40, 0x00], // 1
+    [0x42, 0x61, 0x51, 0x49, 0x46], // 2
+    [0x21, 0x41, 0x45, 0x4B, 0x31], // 3
+    [0x18, 0x14, 0x12, 0x7F, 0x10], // 4
+    [0x27, 0x45, 0x45, 0x45, 0x39], // 5
+    [0x3C, 0x4A, 0x49, 0x49, 0x30], // 6
+    [0x01, 0x71, 0x09, 0x05, 0x03], // 7
+    [0x36, 0x49, 0x49, 0x49, 0x36], // 8
+    [0x06, 0x49, 0x49, 0x29, 0x1E], // 9
+    [0x00, 0x36, 0x36, 0x00, 0x00], // :
+    [0x00, 0x56, 0x36, 0x00, 0x00], // ;
+    [0x08, 0x14, 0x22, 0x41, 0x00], // <
+    [0x14, 0x14, 0x14, 0x14, 0x14], // =
+    [0x00, 0x41, 0x22, 0x14, 0x08], // >
+    [0x02, 0x01, 0x51, 0x09, 0x06], // ?
+    [0x32, 0x49, 0x79, 0x41, 0x3E], // @
+    [0x7E, 0x11, 0x11, 0x11, 0x7E], // A
+    [0x7F, 0x49, 0x49, 0x49, 0x36], // B
+    [0x3E, 0x41, 0x41, 0x41, 0x22], // C
+    [0x7F, 0x41, 0x41, 0x22, 0x1C], // D
+    [0x7F, 0x49, 0x49, 0x49, 0x41], // E
+    [0x7F, 0x09, 0x09, 0x09, 0x01], // F
+    [0x3E, 0x41, 0x49, 0x49, 0x7A], // G
+    [0x7F, 0x08, 0x08, 0x08, 0x7F], // H
+    [0x00, 0x41, 0x7F, 0x41, 0x00], // I
+    [0x20, 0x40, 0x41, 0x3F, 0x01], // J
+    [0x7F, 0x08, 0x14, 0x22, 0x41], // K
+    [0x7F, 0x40, 0x40, 0x40, 0x40], // L
+    [0x7F, 0x02, 0x0C, 0x02, 0x7F], // M
+    [0x7F, 0x04, 0x08, 0x10, 0x7F], // N
+    [0x3E, 0x41, 0x41, 0x41, 0x3E], // O
+    [0x7F, 0x09, 0x09, 0x09, 0x06], // P
+    [0x3E, 0x41, 0x51, 0x21, 0x5E], // Q
+    [0x7F, 0x09, 0x19, 0x29, 0x46], // R
+    [0x46, 0x49, 0x49, 0x49, 0x31], // S
+    [0x01, 0x01, 0x7F, 0x01, 0x01], // T
+    [0x3F, 0x40, 0x40, 0x40, 0x3F], // U
+    [0x1F, 0x20, 0x40, 0x20, 0x1F], // V
+    [0x3F, 0x40, 0x38, 0x40, 0x3F], // W
+    [0x63, 0x14, 0x08, 0x14, 0x63], // X
+    [0x07, 0x08, 0x70, 0x08, 0x07], // Y
+    [0x61, 0x51, 0x49, 0x45, 0x43], // Z
+    [0x00, 0x7F, 0x41, 0x41, 0x00], // [
+    [0x02, 0x04, 0x08, 0x10, 0x20], // \
+    [0x00, 0x41, 0x41, 0x7F, 0x00], // ]
+    [0x04, 0x02, 0x01, 0x02, 0x04], // ^
+    [0x40, 0x40, 0x40, 0x40, 0x40], // _
+    [0x00, 0x01, 0x02, 0x04, 0x00], // `
+    [0x20, 0x54, 0x54, 0x54, 0x78], // a
+    [0x7F, 0x48, 0x44, 0x44, 0x38], // b
+    [0x38, 0x44, 0x44, 0x44, 0x20], // c
+    [0x38, 0x44, 0x44, 0x48, 0x7F], // d
+    [0x38, 0x54, 0x54, 0x54, 0x18], // e
+    [0x08, 0x7E, 0x09, 0x01, 0x02], // f
+    [0x0C, 0x52, 0x52, 0x52, 0x3E], // g
+    [0x7F, 0x08, 0x04, 0x04, 0x78], // h
+    [0x00, 0x44, 0x7D, 0x40, 0x00], // i
+    [0x20, 0x40, 0x44, 0x3D, 0x00], // j
+    [0x7F, 0x10, 0x28, 0x44, 0x00], // k
+    [0x00, 0x41, 0x7F, 0x40, 0x00], // l
+    [0x7C, 0x04, 0x18, 0x04, 0x78], // m
+    [0x7C, 0x08, 0x04, 0x04, 0x78], // n
+    [0x38, 0x44, 0x44, 0x44, 0x38], // o
+    [0x7C, 0x14, 0x14, 0x14, 0x08], // p
+    [0x08, 0x14, 0x14, 0x18, 0x7C], // q
+    [0x7C, 0x08, 0x04, 0x04, 0x08], // r
+    [0x48, 0x54, 0x54, 0x54, 0x20], // s
+    [0x04, 0x3F, 0x44, 0x40, 0x20], // t
+    [0x3C, 0x40, 0x40, 0x20, 0x7C], // u
+    [0x1C, 0x20, 0x40, 0x20, 0x1C], // v
+    [0x3C, 0x40, 0x30, 0x40, 0x3C], // w
+    [0x44, 0x28, 0x10, 0x28, 0x44], // x
+    [0x0C, 0x50, 0x50, 0x50, 0x3C], // y
+    [0x44, 0x64, 0x54, 0x4C, 0x44], // z
+    [0x00, 0x08, 0x36, 0x41, 0x00], // {
+    [0x00, 0x00, 0x7F, 0x00, 0x00], // |
+    [0x00, 0x41, 0x36, 0x08, 0x00], // }
+    [0x10, 0x08, 0x08, 0x10, 0x08], // ~
+];
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redrawing_same_content_is_clean() {
+        let mut frame = Framebuffer::new();
+        frame.text(0, 0, "Menu", true);
+        frame.shown = frame.buffer;
+
+        frame.clear();
+        frame.text(0, 0, "Menu", true);
+        assert!(!frame.is_dirty());
+    }
+
+    #[test]
+    fn dirty_region_covers_changes_only() {
+        let mut frame = Framebuffer::new();
+        frame.set_pixel(10, 9, true);
+        frame.set_pixel(20, 30, true);
+        assert_eq!(frame.dirty(), (0b1010, 10..21));
+
+        frame.mark_all_dirty();
+        assert_eq!(frame.dirty(), (0xFF, 0..DISPLAY_WIDTH));
+    }
+
+    // Top left `width` x `height` corner of the PBM dump, one string per row
+    fn pbm_corner(frame: &Framebuffer, width: usize, height: usize) -> Vec<String> {
+        let mut out = String::new();
+        frame.write_pbm(&mut out).unwrap();
+        out.lines().skip(2).take(height).map(|row| row[..width].to_string()).collect()
+    }
+
+    #[test]
+    fn line_includes_both_ends() {
+        let mut frame = Framebuffer::new();
+        frame.line(0, 0, 4, 2, true);
+        frame.line(6, 3, 6, 0, true);
+        assert_eq!(pbm_corner(&frame, 8, 4), [
+            "10000010",
+            "01100010",
+            "00011010",
+            "00000010",
+        ]);
+    }
+
+    #[test]
+    fn circle_is_symmetric() {
+        let mut frame = Framebuffer::new();
+        frame.circle(3, 3, 3, true);
+        assert_eq!(pbm_corner(&frame, 7, 7), [
+            "0011100",
+            "0100010",
+            "1000001",
+            "1000001",
+            "1000001",
+            "0100010",
+            "0011100",
+        ]);
+    }
+
+    #[test]
+    fn text_uses_5x7_font() {
+        let mut frame = Framebuffer::new();
+        assert_eq!(frame.text(0, 0, "T\nI", true), (CHAR_WIDTH, LINE_HEIGHT));
+        assert_eq!(pbm_corner(&frame, 6, 15), [
+            "111110",
+            "001000",
+            "001000",
+            "001000",
+            "001000",
+            "001000",
+            "001000",
+            "000000",
+            "011100",
+            "001000",
+            "001000",
+            "001000",
+            "001000",
+            "001000",
+            "011100",
+        ]);
+    }
+
+    #[test]
+    fn bitmap_rows_are_msb_first_and_padded() {
+        let mut frame = Framebuffer::new();
+        // 10 pixels wide, second byte of each row holds the last 2
+        frame.bitmap(1, 1, 10, 2, &[0b1010_0000, 0b0100_0000, 0b0000_0001, 0b1000_0000], true);
+        assert_eq!(pbm_corner(&frame, 12, 3), [
+            "000000000000",
+            "010100000010",
+            "000000001100",
+        ]);
+    }
+
+    #[test]
+    fn pbm_has_header_and_pixels() {
+        let mut frame = Framebuffer::new();
+        frame.set_pixel(1, 0, true);
+        let mut out = String::new();
+        frame.write_pbm(&mut out).unwrap();
+
+        let mut lines = out.lines();
+        assert_eq!(lines.next(), Some("P1"));
+        assert_eq!(lines.next(), Some("128 64"));
+        assert!(lines.next().unwrap().starts_with("0100"));
+        assert_eq!(lines.count(), DISPLAY_HEIGHT - 1);
+    }
+}
@@ -0,0 +1,347 @@
+// FPC BM-Lite host communication protocol (HCP)
+//
+// Application packet: command, argument count and arguments (id, size, data).
+// Transport splits the packet into numbered frames, link wraps each of them
+// with channel, size and CRC32, and every link frame is answered by an ACK.
+// All integers are little endian. Everything here works on plain byte
+// slices, `BmLite` moves the frames over SPI.
+
+use crate::device::SpiError;
+
+/// Largest link frame exchanged with the sensor
+pub const HCP_MTU: usize = 256;
+/// Channel and size in front of link payload
+pub const LINK_HEADER: usize = 4;
+/// CRC32 behind link payload
+pub const LINK_CRC: usize = 4;
+/// Size, sequence number and length in front of packet chunk
+pub const TRANSPORT_HEADER: usize = 6;
+/// Packet bytes carried by one transport frame
+pub const HCP_CHUNK: usize = HCP_MTU - LINK_HEADER - LINK_CRC - TRANSPORT_HEADER;
+/// Largest packet `BmLite::call` builds on the stack
+pub const HCP_COMMAND_LEN: usize = 256;
+
+const HCP_CHANNEL: u16 = 0x0001;
+/// Link payload acknowledging a frame
+pub const HCP_ACK: [u8; 4] = 0x7F01_FF7Fu32.to_le_bytes();
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+#[repr(u16)]
+pub enum Command {
+    Capture = 0x0001,
+    Enroll = 0x0002,
+    Identify = 0x0003,
+    Match = 0x0004,
+    Image = 0x0005,
+    Template = 0x0006,
+    StorageTemplate = 0x0007,
+    Sensor = 0x0008,
+    Info = 0x0009,
+    Reset = 0x0014,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+#[repr(u16)]
+pub enum Arg {
+    Result = 0x0001,
+    Count = 0x0002,
+    Timeout = 0x0003,
+    Id = 0x0004,
+    All = 0x0005,
+    Start = 0x0006,
+    Add = 0x0007,
+    Finish = 0x0008,
+    Upload = 0x0009,
+    Download = 0x000A,
+    Create = 0x000B,
+    Save = 0x000C,
+    Delete = 0x000D,
+    Data = 0x000E,
+    Update = 0x000F,
+    SeqNr = 0x0010,
+    SeqLen = 0x0011,
+    Match = 0x0012,
+    FingerDown = 0x0013,
+    Get = 0x0014,
+    Version = 0x0015,
+    Extract = 0x0016,
+}
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum BmLiteError {
+    Spi(SpiError),
+    /// Sensor didn't raise IRQ in time
+    Timeout,
+    /// Link frame CRC mismatch
+    Crc,
+    /// Malformed link, transport or application data
+    Frame,
+    /// Link frame wasn't acknowledged
+    NoAck,
+    /// Packet doesn't fit the given buffer
+    BufferTooSmall,
+    /// Sensor reported error code in `Arg::Result`
+    Result(i16),
+    /// Capture of another workflow is still running
+    Busy,
+}
+
+impl From<SpiError> for BmLiteError {
+    fn from(error: SpiError) -> Self {
+        BmLiteError::Spi(error)
+    }
+}
+
+
+/// CRC-32 (IEEE 802.3) used by the link layer
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { crc >> 1 ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+/// Wrap `payload` into link frame in `out`, returns frame length
+pub fn link_encode(payload: &[u8], out: &mut [u8]) -> Result<usize, BmLiteError> {
+    let len = LINK_HEADER + payload.len() + LINK_CRC;
+    if out.len() < len || payload.len() > u16::MAX as usize {
+        return Err(BmLiteError::BufferTooSmall);
+    }
+    out[0..2].copy_from_slice(&HCP_CHANNEL.to_le_bytes());
+    out[2..4].copy_from_slice(&(payload.len() as u16).to_le_bytes());
+    out[LINK_HEADER..len - LINK_CRC].copy_from_slice(payload);
+    out[len - LINK_CRC..len].copy_from_slice(&crc32(payload).to_le_bytes());
+    Ok(len)
+}
+
+/// Payload size announced by link frame header
+pub fn link_payload_len(header: &[u8]) -> Result<usize, BmLiteError> {
+    match header {
+        [_, _, low, high, ..] => Ok(u16::from_le_bytes([*low, *high]) as usize),
+        _ => Err(BmLiteError::Frame),
+    }
+}
+
+/// Check link frame and return its payload
+pub fn link_decode(frame: &[u8]) -> Result<&[u8], BmLiteError> {
+    let len = link_payload_len(frame)?;
+    if frame.len() < LINK_HEADER + len + LINK_CRC {
+        return Err(BmLiteError::Frame);
+    }
+    let payload = &frame[LINK_HEADER..LINK_HEADER + len];
+    let crc = &frame[LINK_HEADER + len..LINK_HEADER + len + LINK_CRC];
+    if crc != crc32(payload).to_le_bytes() {
+        return Err(BmLiteError::Crc);
+    }
+    Ok(payload)
+}
+
+
+/// Header of transport frame, `seq_nr` counts from 1 up to `seq_len`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub struct TransportHeader {
+    pub size: u16,
+    pub seq_nr: u16,
+    pub seq_len: u16,
+}
+
+impl TransportHeader {
+    pub fn encode(&self, out: &mut [u8; TRANSPORT_HEADER]) {
+        out[0..2].copy_from_slice(&self.size.to_le_bytes());
+        out[2..4].copy_from_slice(&self.seq_nr.to_le_bytes());
+        out[4..6].copy_from_slice(&self.seq_len.to_le_bytes());
+    }
+
+    /// Split transport frame into header and packet chunk
+    pub fn decode(frame: &[u8]) -> Result<(TransportHeader, &[u8]), BmLiteError> {
+        if frame.len() < TRANSPORT_HEADER {
+            return Err(BmLiteError::Frame);
+        }
+        let field = |at: usize| u16::from_le_bytes([frame[at], frame[at + 1]]);
+        let header = TransportHeader { size: field(0), seq_nr: field(2), seq_len: field(4) };
+        let chunk = frame.get(TRANSPORT_HEADER..TRANSPORT_HEADER + header.size as usize)
+            .ok_or(BmLiteError::Frame)?;
+        if header.seq_nr == 0 || header.seq_nr > header.seq_len {
+            return Err(BmLiteError::Frame);
+        }
+        Ok((header, chunk))
+    }
+}
+
+
+/// Builds application packet in a caller buffer
+pub struct PacketWriter<'a> {
+    buffer: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> PacketWriter<'a> {
+    pub fn new(buffer: &'a mut [u8], command: Command) -> Result<Self, BmLiteError> {
+        if buffer.len() < 4 {
+            return Err(BmLiteError::BufferTooSmall);
+        }
+        buffer[0..2].copy_from_slice(&(command as u16).to_le_bytes());
+        buffer[2..4].copy_from_slice(&0u16.to_le_bytes());
+        Ok(PacketWriter { buffer, len: 4 })
+    }
+
+    pub fn arg(&mut self, id: Arg, data: &[u8]) -> Result<&mut Self, BmLiteError> {
+        let end = self.len + 4 + data.len();
+        if end > self.buffer.len() || data.len() > u16::MAX as usize {
+            return Err(BmLiteError::BufferTooSmall);
+        }
+        self.buffer[self.len..self.len + 2].copy_from_slice(&(id as u16).to_le_bytes());
+        self.buffer[self.len + 2..self.len + 4].copy_from_slice(&(data.len() as u16).to_le_bytes());
+        self.buffer[self.len + 4..end].copy_from_slice(data);
+        self.len = end;
+
+        let count = u16::from_le_bytes([self.buffer[2], self.buffer[3]]) + 1;
+        self.buffer[2..4].copy_from_slice(&count.to_le_bytes());
+        Ok(self)
+    }
+
+    pub fn finish(self) -> &'a [u8] {
+        &self.buffer[..self.len]
+    }
+}
+
+
+/// Received application packet
+#[derive(Clone, Copy)]
+pub struct Packet<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Packet<'a> {
+    /// Checks that all announced arguments are present
+    pub fn parse(bytes: &'a [u8]) -> Result<Self, BmLiteError> {
+        if bytes.len() < 4 {
+            return Err(BmLiteError::Frame);
+        }
+        let packet = Packet { bytes };
+        let count = u16::from_le_bytes([bytes[2], bytes[3]]) as usize;
+        if packet.args().count() != count {
+            return Err(BmLiteError::Frame);
+        }
+        Ok(packet)
+    }
+
+    pub fn command(&self) -> u16 {
+        u16::from_le_bytes([self.bytes[0], self.bytes[1]])
+    }
+
+    /// Arguments as (id, data), stops at the first truncated one
+    pub fn args(&self) -> impl Iterator<Item = (u16, &'a [u8])> {
+        let mut rest = &self.bytes[4..];
+        core::iter::from_fn(move || {
+            if rest.len() < 4 {
+                return None;
+            }
+            let id = u16::from_le_bytes([rest[0], rest[1]]);
+            let size = u16::from_le_bytes([rest[2], rest[3]]) as usize;
+            let data = rest.get(4..4 + size)?;
+            rest = &rest[4 + size..];
+            Some((id, data))
+        })
+    }
+
+    pub fn arg(&self, id: Arg) -> Option<&'a [u8]> {
+        self.args().find(|(arg, _)| *arg == id as u16).map(|(_, data)| data)
+    }
+
+    pub fn arg_u16(&self, id: Arg) -> Option<u16> {
+        match self.arg(id)? {
+            [low, high, ..] => Some(u16::from_le_bytes([*low, *high])),
+            _ => None,
+        }
+    }
+
+    /// Sensor result code, 0 means success
+    pub fn result(&self) -> Option<i16> {
+        self.arg_u16(Arg::Result).map(|code| code as i16)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(&[]), 0);
+    }
+
+    #[test]
+    fn link_round_trip() {
+        let mut frame = [0u8; 16];
+        let len = link_encode(&[1, 2, 3], &mut frame).unwrap();
+        assert_eq!(len, LINK_HEADER + 3 + LINK_CRC);
+        assert_eq!(frame[..4], [0x01, 0x00, 0x03, 0x00]);
+        assert_eq!(link_payload_len(&frame), Ok(3));
+        assert_eq!(link_decode(&frame[..len]), Ok(&[1u8, 2, 3][..]));
+    }
+
+    #[test]
+    fn link_rejects_bad_crc_and_short_frame() {
+        let mut frame = [0u8; 16];
+        let len = link_encode(&[1, 2, 3], &mut frame).unwrap();
+        frame[LINK_HEADER] ^= 0xFF;
+        assert_eq!(link_decode(&frame[..len]), Err(BmLiteError::Crc));
+        assert_eq!(link_decode(&frame[..len - 1]), Err(BmLiteError::Frame));
+        assert_eq!(link_encode(&[0; 9], &mut frame), Err(BmLiteError::BufferTooSmall));
+    }
+
+    #[test]
+    fn transport_header_round_trip() {
+        let header = TransportHeader { size: 2, seq_nr: 1, seq_len: 3 };
+        let mut frame = [0u8; TRANSPORT_HEADER + 2];
+        let mut encoded = [0u8; TRANSPORT_HEADER];
+        header.encode(&mut encoded);
+        frame[..TRANSPORT_HEADER].copy_from_slice(&encoded);
+        frame[TRANSPORT_HEADER..].copy_from_slice(&[0xAA, 0xBB]);
+
+        assert_eq!(TransportHeader::decode(&frame), Ok((header, &[0xAA, 0xBB][..])));
+        assert_eq!(TransportHeader::decode(&frame[..TRANSPORT_HEADER + 1]), Err(BmLiteError::Frame));
+    }
+
+    #[test]
+    fn transport_header_rejects_bad_sequence() {
+        let mut frame = [0u8; TRANSPORT_HEADER];
+        TransportHeader { size: 0, seq_nr: 2, seq_len: 1 }.encode(&mut frame);
+        assert_eq!(TransportHeader::decode(&frame), Err(BmLiteError::Frame));
+    }
+
+    #[test]
+    fn written_packet_parses() {
+        let mut buffer = [0u8; 32];
+        let mut writer = PacketWriter::new(&mut buffer, Command::Enroll).unwrap();
+        writer.arg(Arg::Start, &[]).unwrap();
+        writer.arg(Arg::Result, &(-3i16).to_le_bytes()).unwrap();
+        let bytes = writer.finish();
+
+        let packet = Packet::parse(bytes).unwrap();
+        assert_eq!(packet.command(), Command::Enroll as u16);
+        assert_eq!(packet.args().count(), 2);
+        assert_eq!(packet.arg(Arg::Start), Some(&[][..]));
+        assert_eq!(packet.result(), Some(-3));
+        assert_eq!(packet.arg(Arg::Id), None);
+
+        // Announced argument missing
+        assert_eq!(Packet::parse(&bytes[..bytes.len() - 1]).err(), Some(BmLiteError::Frame));
+    }
+
+    #[test]
+    fn packet_writer_checks_space() {
+        let mut buffer = [0u8; 8];
+        let mut writer = PacketWriter::new(&mut buffer, Command::Info).unwrap();
+        assert_eq!(writer.arg(Arg::Data, &[1]).err(), Some(BmLiteError::BufferTooSmall));
+    }
+}
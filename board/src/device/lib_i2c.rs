@@ -1,4 +1,687 @@
 use crate::hal_main as hal;
-pub use hal::{Twim, twim};
+use crate::device::{LowPower, TWIS_RX_BUF_LEN, TWIS_TX_BUF_LEN};
+pub use hal::{Twim, twim, twis};
+pub use hal::pac::{TWIM0, TWIS1};
 
+use embedded_hal::blocking::i2c::{Read, Write, WriteRead};
+use hal::pac::{p0, P0, P1};
+use hal::target_constants::EASY_DMA_SIZE;
+use core::sync::atomic::{compiler_fence, Ordering::SeqCst};
 
+// 0x00..=0x07 and 0x78..=0x7F are reserved by the I2C specification
+const SCAN_FIRST: u8 = 0x08;
+const SCAN_LAST: u8 = 0x77;
+
+// Cycles of 64 MHz core
+const CYCLES_PER_US: u32 = 64;
+// Half of SCL period during bus clear, ~100 kHz
+const BUS_CLEAR_HALF_PERIOD: u32 = 5 * CYCLES_PER_US;
+const BUS_CLEAR_PULSES: u8 = 9;
+
+/// Default slave address of `Twis`
+pub const TWIS_ADDR: u8 = 0x42;
+/// Registers exposed by `Twis`
+pub const TWIS_REGISTERS: usize = 32;
+
+const RAM_START: usize = 0x2000_0000;
+const RAM_END: usize = 0x2004_0000;
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum I2cError {
+    /// Nobody acknowledged the address
+    AddressNack,
+    /// Slave didn't acknowledge a data byte
+    DataNack,
+    /// Byte received before the previous one was taken by EasyDMA
+    Overrun,
+    /// Transfer didn't finish within `I2cConfig::timeout_us`
+    Timeout,
+    /// SDA is still held low after bus clear
+    BusStuck,
+    /// Buffer longer than EasyDMA can move
+    BufferTooLong,
+    /// EasyDMA reads RAM only
+    BufferNotInRam,
+    /// `I2cProxy` is bound to another address
+    WrongDevice,
+}
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub struct I2cConfig {
+    /// Longest time one transfer may take
+    pub timeout_us: u32,
+    /// Extra attempts after a failed transfer, address NACK isn't retried
+    pub retries: u8,
+}
+
+impl Default for I2cConfig {
+    fn default() -> Self {
+        I2cConfig {
+            timeout_us: 10_000,
+            retries: 2,
+        }
+    }
+}
+
+
+/// TWIM master with error decoding, timeouts, retries and bus recovery
+///
+/// A timed out transfer or a bus stuck low triggers bus clear before
+/// the next attempt.
+pub struct I2c<T: twim::Instance> {
+    twim: T,
+    pins: twim::Pins,
+    config: I2cConfig,
+}
+
+impl<T> I2c<T>
+where
+    T: twim::Instance,
+{
+    pub fn new(twim: T, pins: twim::Pins, frequency: twim::Frequency, config: I2cConfig) -> Self {
+        let mut i2c = I2c { twim, pins, config };
+
+        // Slave may still hold SDA after reset of the MCU
+        if !i2c.sda_high() {
+            i2c.bus_clear().ok();
+        }
+        i2c.connect();
+        i2c.twim.frequency.write(|w| w.frequency().variant(frequency));
+        i2c
+    }
+
+    pub fn free(self) -> (T, twim::Pins) {
+        self.twim.enable.write(|w| w.enable().disabled());
+        (self.twim, self.pins)
+    }
+
+    pub fn config(&self) -> I2cConfig {
+        self.config
+    }
+
+    /// SDA held low while no transfer runs
+    pub fn is_bus_stuck(&self) -> bool {
+        !self.sda_high()
+    }
+
+    pub fn set_config(&mut self, config: I2cConfig) {
+        self.config = config;
+    }
+
+    /// Free the bus from a slave holding SDA low
+    ///
+    /// SCL is clocked by GPIO until SDA is released (at most 9 times),
+    /// then STOP condition is generated.
+    pub fn bus_clear(&mut self) -> Result<(), I2cError> {
+        self.twim.enable.write(|w| w.enable().disabled());
+
+        // Open drain outputs, both lines released
+        for psel in [self.pins.scl.psel_bits(), self.pins.sda.psel_bits()] {
+            let port = port(psel);
+            port.outset.write(|w| unsafe { w.bits(1 << (psel & 0x1F)) });
+            port.pin_cnf[(psel & 0x1F) as usize].write(|w| {
+                w.dir().output().input().connect().pull().pullup().drive().s0d1()
+            });
+        }
+        cortex_m::asm::delay(BUS_CLEAR_HALF_PERIOD);
+
+        for _ in 0..BUS_CLEAR_PULSES {
+            if self.sda_high() {
+                break;
+            }
+            self.drive(self.pins.scl.psel_bits(), false);
+            self.drive(self.pins.scl.psel_bits(), true);
+        }
+
+        // STOP, SDA rises while SCL is high
+        self.drive(self.pins.sda.psel_bits(), false);
+        self.drive(self.pins.scl.psel_bits(), true);
+        self.drive(self.pins.sda.psel_bits(), true);
+
+        let released = self.sda_high();
+        self.connect();
+
+        if released { Ok(()) } else { Err(I2cError::BusStuck) }
+    }
+
+    /// Write `tx` then read `rx` in one transaction, either may be empty
+    pub fn transfer(&mut self, address: u8, tx: &[u8], rx: &mut [u8]) -> Result<(), I2cError> {
+        let mut attempts = self.config.retries as u32 + 1;
+        loop {
+            attempts -= 1;
+            let result = self.transfer_once(address, tx, rx);
+            match result {
+                Ok(()) | Err(I2cError::AddressNack) | Err(I2cError::BufferTooLong)
+                    | Err(I2cError::BufferNotInRam) => return result,
+                Err(_) if attempts == 0 => return result,
+                // A bus still stuck after this shows up on the next attempt
+                Err(I2cError::Timeout) | Err(I2cError::BusStuck) => {
+                    self.bus_clear().ok();
+                },
+                Err(_) => {},
+            }
+        }
+    }
+
+    fn transfer_once(&mut self, address: u8, tx: &[u8], rx: &mut [u8]) -> Result<(), I2cError> {
+        for buffer in [tx, &*rx] {
+            if buffer.len() > EASY_DMA_SIZE {
+                return Err(I2cError::BufferTooLong);
+            }
+            let start = buffer.as_ptr() as usize;
+            if !buffer.is_empty() && !(RAM_START..RAM_END).contains(&start) {
+                return Err(I2cError::BufferNotInRam);
+            }
+        }
+        if tx.is_empty() && rx.is_empty() {
+            return Ok(());
+        }
+        if !self.sda_high() {
+            return Err(I2cError::BusStuck);
+        }
+
+        compiler_fence(SeqCst);
+
+        let twim = &self.twim;
+        twim.address.write(|w| unsafe { w.address().bits(address) });
+        twim.txd.ptr.write(|w| unsafe { w.ptr().bits(tx.as_ptr() as u32) });
+        twim.txd.maxcnt.write(|w| unsafe { w.maxcnt().bits(tx.len() as _) });
+        twim.rxd.ptr.write(|w| unsafe { w.ptr().bits(rx.as_mut_ptr() as u32) });
+        twim.rxd.maxcnt.write(|w| unsafe { w.maxcnt().bits(rx.len() as _) });
+
+        twim.events_stopped.reset();
+        twim.events_error.reset();
+        twim.events_lasttx.reset();
+        twim.events_lastrx.reset();
+        // Error source bits are cleared by writing 1
+        twim.errorsrc.write(|w| w.overrun().bit(true).anack().bit(true).dnack().bit(true));
+
+        // Shorts end the transaction with STOP, TX goes first when present
+        match (tx.is_empty(), rx.is_empty()) {
+            (false, true) => {
+                twim.shorts.write(|w| w.lasttx_stop().enabled());
+                twim.tasks_starttx.write(|w| unsafe { w.bits(1) });
+            },
+            (true, false) => {
+                twim.shorts.write(|w| w.lastrx_stop().enabled());
+                twim.tasks_startrx.write(|w| unsafe { w.bits(1) });
+            },
+            _ => {
+                twim.shorts.write(|w| w.lasttx_startrx().enabled().lastrx_stop().enabled());
+                twim.tasks_starttx.write(|w| unsafe { w.bits(1) });
+            },
+        }
+
+        let finished = self.wait_stopped();
+        let errorsrc = self.twim.errorsrc.read();
+        self.twim.shorts.reset();
+        compiler_fence(SeqCst);
+
+        if errorsrc.anack().bit_is_set() {
+            Err(I2cError::AddressNack)
+        } else if errorsrc.dnack().bit_is_set() {
+            Err(I2cError::DataNack)
+        } else if errorsrc.overrun().bit_is_set() {
+            Err(I2cError::Overrun)
+        } else if !finished {
+            Err(I2cError::Timeout)
+        } else {
+            Ok(())
+        }
+    }
+
+    // Wait for STOPPED, on ERROR or timeout STOP is forced,
+    // returns false when the transfer timed out
+    fn wait_stopped(&mut self) -> bool {
+        let mut left = self.config.timeout_us;
+        loop {
+            if self.twim.events_stopped.read().bits() != 0 {
+                return true;
+            }
+            if self.twim.events_error.read().bits() != 0 {
+                break;
+            }
+            if left == 0 {
+                self.twim.tasks_stop.write(|w| unsafe { w.bits(1) });
+                // STOP can't go out while the bus is held, don't wait for it
+                self.twim.enable.write(|w| w.enable().disabled());
+                self.twim.enable.write(|w| w.enable().enabled());
+                return false;
+            }
+            left -= 1;
+            cortex_m::asm::delay(CYCLES_PER_US);
+        }
+
+        self.twim.tasks_stop.write(|w| unsafe { w.bits(1) });
+        let mut left = self.config.timeout_us;
+        while self.twim.events_stopped.read().bits() == 0 && left > 0 {
+            left -= 1;
+            cortex_m::asm::delay(CYCLES_PER_US);
+        }
+        true
+    }
+
+    // Pins in the mode TWIM expects and the peripheral on
+    fn connect(&mut self) {
+        for psel in [self.pins.scl.psel_bits(), self.pins.sda.psel_bits()] {
+            port(psel).pin_cnf[(psel & 0x1F) as usize].write(|w| {
+                w.dir().input().input().connect().pull().pullup().drive().s0d1().sense().disabled()
+            });
+        }
+        self.twim.psel.scl.write(|w| unsafe { w.bits(self.pins.scl.psel_bits()) });
+        self.twim.psel.sda.write(|w| unsafe { w.bits(self.pins.sda.psel_bits()) });
+        self.twim.enable.write(|w| w.enable().enabled());
+    }
+
+    fn sda_high(&self) -> bool {
+        let psel = self.pins.sda.psel_bits();
+        port(psel).in_.read().bits() & 1 << (psel & 0x1F) != 0
+    }
+
+    fn drive(&self, psel: u32, high: bool) {
+        let port = port(psel);
+        if high {
+            port.outset.write(|w| unsafe { w.bits(1 << (psel & 0x1F)) });
+        } else {
+            port.outclr.write(|w| unsafe { w.bits(1 << (psel & 0x1F)) });
+        }
+        cortex_m::asm::delay(BUS_CLEAR_HALF_PERIOD);
+    }
+}
+
+impl<T: twim::Instance> LowPower for I2c<T> {
+    fn prepare_for_sleep(&mut self) {
+        self.twim.tasks_stop.write(|w| unsafe { w.bits(1) });
+        self.twim.enable.write(|w| w.enable().disabled());
+    }
+
+    fn resume(&mut self) {
+        self.connect();
+    }
+}
+
+// GPIO port of PSEL value, bit 5 selects P1
+fn port(psel: u32) -> &'static p0::RegisterBlock {
+    if psel & 0x20 == 0 {
+        unsafe { &*P0::ptr() }
+    } else {
+        unsafe { &*P1::ptr() }
+    }
+}
+
+impl<T: twim::Instance> Write for I2c<T> {
+    type Error = I2cError;
+
+    fn write(&mut self, address: u8, bytes: &[u8]) -> Result<(), I2cError> {
+        self.transfer(address, bytes, &mut [])
+    }
+}
+
+impl<T: twim::Instance> Read for I2c<T> {
+    type Error = I2cError;
+
+    fn read(&mut self, address: u8, buffer: &mut [u8]) -> Result<(), I2cError> {
+        self.transfer(address, &[], buffer)
+    }
+}
+
+impl<T: twim::Instance> WriteRead for I2c<T> {
+    type Error = I2cError;
+
+    fn write_read(&mut self, address: u8, bytes: &[u8], buffer: &mut [u8]) -> Result<(), I2cError> {
+        self.transfer(address, bytes, buffer)
+    }
+}
+
+
+/// Addresses which ACKed during `scan`, one bit per 7-bit address
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct I2cScan([u32; 4]);
+
+impl I2cScan {
+    pub fn contains(&self, address: u8) -> bool {
+        address < 0x80 && self.0[address as usize / 32] & 1 << (address % 32) != 0
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.iter().map(|word| word.count_ones() as usize).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Found addresses in ascending order
+    pub fn iter(&self) -> impl Iterator<Item = u8> + '_ {
+        (0..0x80).filter(|address| self.contains(*address))
+    }
+
+    /// Human readable report, one address per line
+    pub fn write_report<W: core::fmt::Write>(&self, out: &mut W) -> core::fmt::Result {
+        write!(out, "I2C scan: {} device(s)\r\n", self.len())?;
+        for address in self.iter() {
+            write!(out, "  0x{:02X}\r\n", address)?;
+        }
+        Ok(())
+    }
+
+    fn insert(&mut self, address: u8) {
+        self.0[address as usize / 32] |= 1 << (address % 32);
+    }
+}
+
+
+/// Probe every non reserved 7-bit address with 1 byte read,
+/// devices which ACK their address are reported
+///
+/// Read is used instead of empty write, TWIM doesn't send the address
+/// for zero length transfers.
+pub fn i2c_scan<I: Read>(i2c: &mut I) -> I2cScan {
+    let mut found = I2cScan::default();
+    let mut byte = [0u8; 1];
+    for address in SCAN_FIRST..=SCAN_LAST {
+        if i2c.read(address, &mut byte).is_ok() {
+            found.insert(address);
+        }
+    }
+    found
+}
+
+
+/// Called for every register written by the master, after the value is stored
+pub type WriteCallback = fn(map: &mut RegisterMap, register: u8, value: u8);
+
+/// Registers of the emulated device, the master addresses them with the
+/// first written byte and the pointer auto increments on every access
+pub struct RegisterMap {
+    registers: [u8; TWIS_REGISTERS],
+    writable: u32,
+    pointer: u8,
+    on_write: Option<WriteCallback>,
+}
+
+impl RegisterMap {
+    /// All registers 0 and read only
+    pub const fn new() -> Self {
+        RegisterMap {
+            registers: [0; TWIS_REGISTERS],
+            writable: 0,
+            pointer: 0,
+            on_write: None,
+        }
+    }
+
+    pub fn get(&self, register: u8) -> u8 {
+        self.registers.get(register as usize).copied().unwrap_or(0xFF)
+    }
+
+    /// Device side update, ignores write protection
+    pub fn set(&mut self, register: u8, value: u8) {
+        if let Some(slot) = self.registers.get_mut(register as usize) {
+            *slot = value;
+        }
+    }
+
+    pub fn set_writable(&mut self, register: u8, writable: bool) {
+        if (register as usize) < TWIS_REGISTERS {
+            self.writable = self.writable & !(1 << register) | (writable as u32) << register;
+        }
+    }
+
+    pub fn is_writable(&self, register: u8) -> bool {
+        (register as usize) < TWIS_REGISTERS && self.writable & 1 << register != 0
+    }
+
+    pub fn set_on_write(&mut self, callback: Option<WriteCallback>) {
+        self.on_write = callback;
+    }
+
+    /// Register the next master read starts from
+    pub fn pointer(&self) -> u8 {
+        self.pointer
+    }
+
+    // Master write, read only registers keep their value
+    fn master_write(&mut self, register: u8, value: u8) {
+        if self.is_writable(register) {
+            self.set(register, value);
+            if let Some(callback) = self.on_write {
+                callback(self, register, value);
+            }
+        }
+    }
+}
+
+impl Default for RegisterMap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum TwisEvent {
+    /// Master wrote `len` registers from `register`
+    Written { register: u8, len: u8 },
+    /// Master read `len` registers from `register`
+    Read { register: u8, len: u8 },
+    /// Overflow, over-read or DMA overrun
+    Error(u8),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum TwisState {
+    Idle,
+    Writing,
+    Reading(u8),
+}
+
+
+/// I2C slave presenting a `RegisterMap`, call `handle` from the TWIS interrupt
+pub struct Twis<T: twis::Instance> {
+    twis: T,
+    pins: twis::Pins,
+    map: RegisterMap,
+    state: TwisState,
+    rx_buffor: u32,
+    tx_buffor: u32,
+}
+
+impl<T> Twis<T>
+where
+    T: twis::Instance,
+{
+    /// `rx_buffor` and `tx_buffor` are RAM addresses of `TWIS_RX_BUF_LEN` and
+    /// `TWIS_TX_BUF_LEN` bytes (`TWIS_RX_BUF_DEF`, `TWIS_TX_BUF_DEF`)
+    pub fn new(twis: T, pins: twis::Pins, address: u8, rx_buffor: u32, tx_buffor: u32) -> Self {
+        for psel in [pins.scl.psel_bits(), pins.sda.psel_bits()] {
+            port(psel).pin_cnf[(psel & 0x1F) as usize].write(|w| {
+                w.dir().input().input().connect().pull().disabled().drive().s0d1().sense().disabled()
+            });
+        }
+        twis.psel.scl.write(|w| unsafe { w.bits(pins.scl.psel_bits()) });
+        twis.psel.sda.write(|w| unsafe { w.bits(pins.sda.psel_bits()) });
+
+        twis.address[0].write(|w| unsafe { w.address().bits(address) });
+        twis.config.write(|w| w.address0().enabled());
+        // Reads past the map clock out 0xFF
+        twis.orc.write(|w| unsafe { w.orc().bits(0xFF) });
+
+        // Bus is held until the handler prepares buffers
+        twis.shorts.write(|w| w.write_suspend().enabled().read_suspend().enabled());
+        twis.intenset.write(|w| w.write().set().read().set().stopped().set().error().set());
+        twis.enable.write(|w| w.enable().enabled());
+
+        Twis {
+            twis,
+            pins,
+            map: RegisterMap::new(),
+            state: TwisState::Idle,
+            rx_buffor,
+            tx_buffor,
+        }
+    }
+
+    pub fn free(self) -> (T, twis::Pins) {
+        self.twis.intenclr.write(|w| unsafe { w.bits(0xFFFF_FFFF) });
+        self.twis.enable.write(|w| w.enable().disabled());
+        (self.twis, self.pins)
+    }
+
+    pub fn set_address(&mut self, address: u8) {
+        self.twis.address[0].write(|w| unsafe { w.address().bits(address) });
+    }
+
+    pub fn map(&self) -> &RegisterMap {
+        &self.map
+    }
+
+    pub fn map_mut(&mut self) -> &mut RegisterMap {
+        &mut self.map
+    }
+
+    /// Service READ, WRITE, STOPPED and ERROR events,
+    /// returns what the master did once its transfer part is over;
+    /// an error wins over a transfer part ending in the same call
+    pub fn handle(&mut self) -> Option<TwisEvent> {
+        let mut event = None;
+
+        if self.twis.events_error.read().bits() != 0 {
+            self.twis.events_error.reset();
+            let source = self.twis.errorsrc.read().bits() as u8;
+            self.twis.errorsrc.write(|w| unsafe { w.bits(source as u32) });
+            event = Some(TwisEvent::Error(source));
+        }
+
+        if self.twis.events_write.read().bits() != 0 {
+            self.twis.events_write.reset();
+            event = event.or(self.finish());
+
+            self.twis.rxd.ptr.write(|w| unsafe { w.ptr().bits(self.rx_buffor) });
+            self.twis.rxd.maxcnt.write(|w| unsafe { w.maxcnt().bits(TWIS_RX_BUF_LEN as _) });
+            self.twis.tasks_preparerx.write(|w| unsafe { w.bits(1) });
+            self.state = TwisState::Writing;
+            self.twis.tasks_resume.write(|w| unsafe { w.bits(1) });
+        }
+
+        if self.twis.events_read.read().bits() != 0 {
+            self.twis.events_read.reset();
+            // Pointer written just before the repeated start is applied first
+            event = event.or(self.finish());
+
+            let tx = unsafe {
+                core::slice::from_raw_parts_mut(self.tx_buffor as *mut u8, TWIS_TX_BUF_LEN)
+            };
+            let pointer = self.map.pointer;
+            for (offset, byte) in tx.iter_mut().enumerate() {
+                *byte = self.map.get(pointer.wrapping_add(offset as u8));
+            }
+            compiler_fence(SeqCst);
+
+            self.twis.txd.ptr.write(|w| unsafe { w.ptr().bits(self.tx_buffor) });
+            self.twis.txd.maxcnt.write(|w| unsafe { w.maxcnt().bits(TWIS_TX_BUF_LEN as _) });
+            self.twis.tasks_preparetx.write(|w| unsafe { w.bits(1) });
+            self.state = TwisState::Reading(pointer);
+            self.twis.tasks_resume.write(|w| unsafe { w.bits(1) });
+        }
+
+        if self.twis.events_stopped.read().bits() != 0 {
+            self.twis.events_stopped.reset();
+            event = event.or(self.finish());
+        }
+
+        event
+    }
+
+    // Apply the transfer part which just ended to the map
+    fn finish(&mut self) -> Option<TwisEvent> {
+        compiler_fence(SeqCst);
+        let state = core::mem::replace(&mut self.state, TwisState::Idle);
+
+        match state {
+            TwisState::Idle => None,
+            TwisState::Writing => {
+                let amount = (self.twis.rxd.amount.read().bits() as usize).min(TWIS_RX_BUF_LEN);
+                let rx = unsafe {
+                    core::slice::from_raw_parts(self.rx_buffor as *const u8, amount)
+                };
+                let (&register, data) = rx.split_first()?;
+                self.map.pointer = register;
+                for &value in data {
+                    self.map.master_write(self.map.pointer, value);
+                    self.map.pointer = self.map.pointer.wrapping_add(1);
+                }
+                if data.is_empty() {
+                    return None;
+                }
+                Some(TwisEvent::Written { register, len: data.len() as u8 })
+            },
+            TwisState::Reading(register) => {
+                let len = self.twis.txd.amount.read().bits() as u8;
+                self.map.pointer = register.wrapping_add(len);
+                Some(TwisEvent::Read { register, len })
+            },
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Bus where only `present` addresses ACK, remembers what was probed
+    struct MockBus {
+        present: &'static [u8],
+        probed: Vec<u8>,
+    }
+
+    impl Read for MockBus {
+        type Error = I2cError;
+
+        fn read(&mut self, address: u8, buffer: &mut [u8]) -> Result<(), I2cError> {
+            self.probed.push(address);
+            if self.present.contains(&address) {
+                buffer.fill(0xA5);
+                Ok(())
+            } else {
+                Err(I2cError::AddressNack)
+            }
+        }
+    }
+
+    #[test]
+    fn scan_reports_acking_addresses() {
+        // 0x00 and 0x7F are reserved and never probed
+        let mut bus = MockBus { present: &[0x00, 0x3C, 0x08, 0x77, 0x7F], probed: Vec::new() };
+        let found = i2c_scan(&mut bus);
+
+        assert_eq!(bus.probed, (SCAN_FIRST..=SCAN_LAST).collect::<Vec<_>>());
+        assert_eq!(found.iter().collect::<Vec<_>>(), [0x08, 0x3C, 0x77]);
+        assert_eq!(found.len(), 3);
+        assert!(found.contains(0x3C));
+        assert!(!found.contains(0x00));
+        assert!(!found.contains(0x80));
+    }
+
+    #[test]
+    fn empty_scan_report() {
+        let mut bus = MockBus { present: &[], probed: Vec::new() };
+        let found = i2c_scan(&mut bus);
+        assert!(found.is_empty());
+
+        let mut report = String::new();
+        found.write_report(&mut report).unwrap();
+        assert_eq!(report, "I2C scan: 0 device(s)\r\n");
+    }
+
+    #[test]
+    fn scan_report_lists_addresses() {
+        let mut bus = MockBus { present: &[0x3C, 0x50], probed: Vec::new() };
+        let mut report = String::new();
+        i2c_scan(&mut bus).write_report(&mut report).unwrap();
+        assert_eq!(report, "I2C scan: 2 device(s)\r\n  0x3C\r\n  0x50\r\n");
+    }
+}
@@ -0,0 +1,384 @@
+// Menu UI for the OLED
+//
+// Menus are `static` trees declared by the application. `MenuNav` is the
+// navigation state machine, it knows nothing about the display, so it can be
+// driven from tests; `render` draws the current screen into a `Framebuffer`.
+
+use crate::device::{ButtonEvent, Framebuffer, CHAR_WIDTH, DISPLAY_HEIGHT, DISPLAY_WIDTH, LINE_HEIGHT};
+
+/// Max nesting of submenus
+pub const MENU_DEPTH: usize = 4;
+/// Value items are addressed by id `0..MENU_VALUES`
+pub const MENU_VALUES: usize = 16;
+const VISIBLE_ROWS: usize = DISPLAY_HEIGHT / LINE_HEIGHT as usize - 1;
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum Nav {
+    Up,
+    Down,
+    Select,
+    Back,
+}
+
+impl Nav {
+    /// Clicks of buttons 1..=4 are up/down/select/back
+    pub fn from_button(event: ButtonEvent) -> Option<Nav> {
+        match event {
+            ButtonEvent::Click(1) => Some(Nav::Up),
+            ButtonEvent::Click(2) => Some(Nav::Down),
+            ButtonEvent::Click(3) => Some(Nav::Select),
+            ButtonEvent::Click(4) => Some(Nav::Back),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ValueSpec {
+    pub min: i32,
+    pub max: i32,
+    pub step: i32,
+    pub initial: i32,
+}
+
+pub enum MenuItem {
+    /// Opens nested menu
+    Submenu(&'static str, &'static Menu),
+    /// Reports `MenuEvent::Action(id)` when selected
+    Action(&'static str, u8),
+    /// Editable number with id `0..MENU_VALUES`
+    Value(&'static str, u8, ValueSpec),
+    /// Opens status screen, application draws its content
+    Status(&'static str, u8),
+}
+
+impl MenuItem {
+    pub fn label(&self) -> &'static str {
+        match self {
+            MenuItem::Submenu(label, _)
+            | MenuItem::Action(label, _)
+            | MenuItem::Value(label, _, _)
+            | MenuItem::Status(label, _) => label,
+        }
+    }
+}
+
+pub struct Menu {
+    pub title: &'static str,
+    pub items: &'static [MenuItem],
+}
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum MenuEvent {
+    /// Screen changed, render it again
+    Redraw,
+    /// Action item selected
+    Action(u8),
+    /// Value editor confirmed new value
+    ValueChanged(u8, i32),
+    /// Status screen opened, application should fill it
+    StatusOpened(u8),
+    /// Back pressed in the root menu
+    Exit,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Browse,
+    // Value id, spec and value before editing
+    Edit(u8, ValueSpec, i32),
+    Status(u8),
+}
+
+pub struct MenuNav {
+    stack: [(&'static Menu, usize); MENU_DEPTH],
+    depth: usize,
+    mode: Mode,
+    values: [i32; MENU_VALUES],
+}
+
+impl MenuNav {
+    pub fn new(root: &'static Menu) -> Self {
+        let mut nav = MenuNav {
+            stack: [(root, 0); MENU_DEPTH],
+            depth: 1,
+            mode: Mode::Browse,
+            values: [0; MENU_VALUES],
+        };
+        nav.load_initial(root, 0);
+        nav
+    }
+
+    /// Menu currently shown
+    pub fn menu(&self) -> &'static Menu {
+        self.stack[self.depth - 1].0
+    }
+
+    /// Index of highlighted item
+    pub fn cursor(&self) -> usize {
+        self.stack[self.depth - 1].1
+    }
+
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// Id of the value being edited
+    pub fn editing(&self) -> Option<u8> {
+        match self.mode {
+            Mode::Edit(id, ..) => Some(id),
+            _ => None,
+        }
+    }
+
+    /// Id of the open status screen
+    pub fn status(&self) -> Option<u8> {
+        match self.mode {
+            Mode::Status(id) => Some(id),
+            _ => None,
+        }
+    }
+
+    pub fn value(&self, id: u8) -> i32 {
+        self.values.get(id as usize).copied().unwrap_or(0)
+    }
+
+    pub fn set_value(&mut self, id: u8, value: i32) {
+        if let Some(slot) = self.values.get_mut(id as usize) {
+            *slot = value;
+        }
+    }
+
+    pub fn handle(&mut self, nav: Nav) -> Option<MenuEvent> {
+        match self.mode {
+            Mode::Browse => self.browse(nav),
+            Mode::Edit(id, spec, before) => {
+                let value = self.value(id);
+                match nav {
+                    Nav::Up => self.set_value(id, value.saturating_add(spec.step).min(spec.max)),
+                    Nav::Down => self.set_value(id, value.saturating_sub(spec.step).max(spec.min)),
+                    Nav::Select => {
+                        self.mode = Mode::Browse;
+                        return Some(MenuEvent::ValueChanged(id, value));
+                    },
+                    Nav::Back => {
+                        self.set_value(id, before);
+                        self.mode = Mode::Browse;
+                    },
+                }
+                Some(MenuEvent::Redraw)
+            },
+            Mode::Status(_) => {
+                if nav == Nav::Back {
+                    self.mode = Mode::Browse;
+                    return Some(MenuEvent::Redraw);
+                }
+                None
+            },
+        }
+    }
+
+    fn browse(&mut self, nav: Nav) -> Option<MenuEvent> {
+        let count = self.menu().items.len();
+        let (_, cursor) = &mut self.stack[self.depth - 1];
+
+        match nav {
+            Nav::Up => {
+                // Wrap around at the ends
+                *cursor = if *cursor == 0 { count.saturating_sub(1) } else { *cursor - 1 };
+                Some(MenuEvent::Redraw)
+            },
+            Nav::Down => {
+                *cursor = if *cursor + 1 >= count { 0 } else { *cursor + 1 };
+                Some(MenuEvent::Redraw)
+            },
+            Nav::Back => {
+                if self.depth == 1 {
+                    return Some(MenuEvent::Exit);
+                }
+                self.depth -= 1;
+                Some(MenuEvent::Redraw)
+            },
+            Nav::Select => match self.menu().items.get(self.cursor())? {
+                MenuItem::Submenu(_, menu) => {
+                    if self.depth == MENU_DEPTH {
+                        return None;
+                    }
+                    self.stack[self.depth] = (menu, 0);
+                    self.depth += 1;
+                    Some(MenuEvent::Redraw)
+                },
+                MenuItem::Action(_, id) => Some(MenuEvent::Action(*id)),
+                MenuItem::Value(_, id, spec) => {
+                    self.mode = Mode::Edit(*id, *spec, self.value(*id));
+                    Some(MenuEvent::Redraw)
+                },
+                MenuItem::Status(_, id) => {
+                    self.mode = Mode::Status(*id);
+                    Some(MenuEvent::StatusOpened(*id))
+                },
+            },
+        }
+    }
+
+    fn load_initial(&mut self, menu: &'static Menu, depth: usize) {
+        for item in menu.items {
+            match item {
+                MenuItem::Value(_, id, spec) => self.set_value(*id, spec.initial),
+                MenuItem::Submenu(_, nested) if depth + 1 < MENU_DEPTH => self.load_initial(nested, depth + 1),
+                _ => {},
+            }
+        }
+    }
+
+    /// Draw current screen; on a status screen only the title bar is drawn,
+    /// the rows below are left to the application
+    pub fn render(&self, frame: &mut Framebuffer) {
+        frame.clear();
+
+        let title = match self.mode {
+            Mode::Browse => self.menu().title,
+            _ => self.menu().items.get(self.cursor()).map_or("", |item| item.label()),
+        };
+        frame.fill_rect(0, 0, DISPLAY_WIDTH as i32, LINE_HEIGHT, true);
+        frame.text(1, 0, title, false);
+
+        match self.mode {
+            Mode::Browse => {
+                let items = self.menu().items;
+                let first = self.cursor().saturating_sub(VISIBLE_ROWS - 1);
+                for (row, item) in items.iter().enumerate().skip(first).take(VISIBLE_ROWS) {
+                    let y = (row - first + 1) as i32 * LINE_HEIGHT;
+                    let selected = row == self.cursor();
+                    if selected {
+                        frame.fill_rect(0, y, DISPLAY_WIDTH as i32, LINE_HEIGHT, true);
+                    }
+                    frame.text(CHAR_WIDTH, y, item.label(), !selected);
+                    if let MenuItem::Submenu(..) = item {
+                        frame.text(DISPLAY_WIDTH as i32 - CHAR_WIDTH, y, ">", !selected);
+                    }
+                }
+            },
+            Mode::Edit(id, ..) => {
+                let mut digits = [0u8; 12];
+                let text = format_value(self.value(id), &mut digits);
+                let y = (DISPLAY_HEIGHT as i32 - LINE_HEIGHT) / 2;
+                let x = (DISPLAY_WIDTH as i32 - (text.len() as i32 + 4) * CHAR_WIDTH) / 2;
+                frame.text(x, y, "< ", true);
+                let (x, _) = frame.text(x + 2 * CHAR_WIDTH, y, text, true);
+                frame.text(x, y, " >", true);
+            },
+            Mode::Status(_) => {},
+        }
+    }
+}
+
+// i32 as decimal text without `core::fmt` machinery
+fn format_value(value: i32, buffer: &mut [u8; 12]) -> &str {
+    let mut number = value.unsigned_abs();
+    let mut start = buffer.len();
+    loop {
+        start -= 1;
+        buffer[start] = b'0' + (number % 10) as u8;
+        number /= 10;
+        if number == 0 {
+            break;
+        }
+    }
+    if value < 0 {
+        start -= 1;
+        buffer[start] = b'-';
+    }
+    core::str::from_utf8(&buffer[start..]).unwrap_or("")
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LEVEL: ValueSpec = ValueSpec { min: i32::MIN, max: i32::MAX, step: 10, initial: i32::MAX - 5 };
+    static SETTINGS: Menu = Menu {
+        title: "Settings",
+        items: &[MenuItem::Value("Level", 2, LEVEL), MenuItem::Status("Info", 7)],
+    };
+    static ROOT: Menu = Menu {
+        title: "Main",
+        items: &[
+            MenuItem::Action("Start", 1),
+            MenuItem::Submenu("Settings", &SETTINGS),
+            MenuItem::Action("Stop", 3),
+        ],
+    };
+
+    #[test]
+    fn cursor_wraps_at_both_ends() {
+        let mut nav = MenuNav::new(&ROOT);
+        assert_eq!(nav.handle(Nav::Up), Some(MenuEvent::Redraw));
+        assert_eq!(nav.cursor(), 2);
+        nav.handle(Nav::Down);
+        assert_eq!(nav.cursor(), 0);
+        assert_eq!(nav.handle(Nav::Select), Some(MenuEvent::Action(1)));
+    }
+
+    #[test]
+    fn submenu_opens_and_back_returns() {
+        let mut nav = MenuNav::new(&ROOT);
+        nav.handle(Nav::Down);
+        nav.handle(Nav::Select);
+        assert_eq!(nav.depth(), 2);
+        assert_eq!(nav.menu().title, "Settings");
+        assert_eq!(nav.cursor(), 0);
+
+        nav.handle(Nav::Back);
+        assert_eq!(nav.depth(), 1);
+        assert_eq!(nav.cursor(), 1);
+        assert_eq!(nav.handle(Nav::Back), Some(MenuEvent::Exit));
+    }
+
+    #[test]
+    fn value_edit_saturates_at_limits() {
+        let mut nav = MenuNav::new(&SETTINGS);
+        nav.handle(Nav::Select);
+        assert_eq!(nav.editing(), Some(2));
+
+        nav.handle(Nav::Up);
+        assert_eq!(nav.value(2), i32::MAX);
+        assert_eq!(nav.handle(Nav::Select), Some(MenuEvent::ValueChanged(2, i32::MAX)));
+
+        nav.set_value(2, i32::MIN + 5);
+        nav.handle(Nav::Select);
+        nav.handle(Nav::Down);
+        assert_eq!(nav.value(2), i32::MIN);
+    }
+
+    #[test]
+    fn value_edit_back_restores() {
+        let mut nav = MenuNav::new(&SETTINGS);
+        nav.handle(Nav::Select);
+        nav.handle(Nav::Down);
+        nav.handle(Nav::Back);
+        assert_eq!(nav.editing(), None);
+        assert_eq!(nav.value(2), LEVEL.initial);
+    }
+
+    #[test]
+    fn status_screen_closes_on_back_only() {
+        let mut nav = MenuNav::new(&SETTINGS);
+        nav.handle(Nav::Down);
+        assert_eq!(nav.handle(Nav::Select), Some(MenuEvent::StatusOpened(7)));
+        assert_eq!(nav.handle(Nav::Up), None);
+        assert_eq!(nav.status(), Some(7));
+        assert_eq!(nav.handle(Nav::Back), Some(MenuEvent::Redraw));
+        assert_eq!(nav.status(), None);
+    }
+
+    #[test]
+    fn negative_values_are_formatted() {
+        let mut digits = [0u8; 12];
+        assert_eq!(format_value(i32::MIN, &mut digits), "-2147483648");
+        assert_eq!(format_value(0, &mut digits), "0");
+    }
+}
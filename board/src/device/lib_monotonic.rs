@@ -0,0 +1,105 @@
+// RTIC monotonic on RTC, ticks at 32.768 kHz from LFCLK
+//
+// RTC counter has 24 bits (~8.5 min), overflows are counted in software for
+// 64-bit time. Unlike SysTick it keeps running with the CPU asleep and the
+// HF clock off, wakeups come from compare 0.
+//
+//     #[monotonic(binds = RTC1, default = true)]
+//     type MyMono = RtcMonotonic<RTC1>;
+
+use crate::hal_main as hal;
+use crate::device::BoardClocks;
+pub use hal::pac::{RTC0, RTC1, RTC2};
+pub use hal::rtc::Instance as RtcInstance;
+pub use fugit::{self, ExtU64};
+
+use rtic_monotonic::Monotonic;
+
+pub const RTC_HZ: u32 = 32_768;
+const COUNTER_BITS: u32 = 24;
+const COUNTER_MASK: u32 = (1 << COUNTER_BITS) - 1;
+// Compare closer than this may be missed, see RTC COMPARE chapter
+const MIN_COMPARE_TICKS: u32 = 3;
+
+
+pub struct RtcMonotonic<T: RtcInstance> {
+    rtc: T,
+    overflows: u64,
+}
+
+impl<T> RtcMonotonic<T>
+where
+    T: RtcInstance,
+{
+    /// Counter stays stopped until RTIC starts it, `clocks` proves LFCLK runs
+    pub fn new(rtc: T, _clocks: &BoardClocks) -> Self {
+        rtc.tasks_stop.write(|w| unsafe { w.bits(1) });
+        rtc.prescaler.write(|w| unsafe { w.prescaler().bits(0) });
+        rtc.intenset.write(|w| w.compare0().set().ovrflw().set());
+        RtcMonotonic { rtc, overflows: 0 }
+    }
+
+    pub fn free(self) -> T {
+        self.rtc.tasks_stop.write(|w| unsafe { w.bits(1) });
+        self.rtc
+    }
+
+    /// Registers, for PPI endpoints on compare 1..=3
+    pub fn registers(&self) -> &hal::pac::rtc0::RegisterBlock {
+        &self.rtc
+    }
+
+    fn counter(&self) -> u32 {
+        self.rtc.counter.read().bits() & COUNTER_MASK
+    }
+}
+
+impl<T> Monotonic for RtcMonotonic<T>
+where
+    T: RtcInstance,
+{
+    // Overflow interrupt keeps the 64-bit time going
+    const DISABLE_INTERRUPT_ON_EMPTY_QUEUE: bool = false;
+
+    type Instant = fugit::TimerInstantU64<RTC_HZ>;
+    type Duration = fugit::TimerDurationU64<RTC_HZ>;
+
+    fn now(&mut self) -> Self::Instant {
+        let counter = self.counter();
+        // Overflow not handled yet, counter already wrapped
+        let pending = self.rtc.events_ovrflw.read().bits() != 0 && counter < COUNTER_MASK / 2;
+        let overflows = self.overflows + pending as u64;
+        Self::Instant::from_ticks(overflows << COUNTER_BITS | counter as u64)
+    }
+
+    fn set_compare(&mut self, instant: Self::Instant) {
+        let now = self.now().ticks();
+        // Far deadlines are approached half a counter period at a time
+        let ahead = instant.ticks().saturating_sub(now)
+            .clamp(MIN_COMPARE_TICKS as u64, (COUNTER_MASK / 2) as u64);
+        let compare = (now + ahead) as u32 & COUNTER_MASK;
+        self.rtc.cc[0].write(|w| unsafe { w.compare().bits(compare) });
+    }
+
+    fn clear_compare_flag(&mut self) {
+        self.rtc.events_compare[0].reset();
+    }
+
+    fn zero() -> Self::Instant {
+        Self::Instant::from_ticks(0)
+    }
+
+    unsafe fn reset(&mut self) {
+        self.rtc.tasks_clear.write(|w| w.bits(1));
+        self.rtc.events_ovrflw.reset();
+        self.overflows = 0;
+        self.rtc.tasks_start.write(|w| w.bits(1));
+    }
+
+    fn on_interrupt(&mut self) {
+        if self.rtc.events_ovrflw.read().bits() != 0 {
+            self.rtc.events_ovrflw.reset();
+            self.overflows += 1;
+        }
+    }
+}
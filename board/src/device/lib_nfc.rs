@@ -10,9 +10,7 @@ impl Nfct   {
         // Turning on interrupt SENSE event
         periph.inten.write(|p| unsafe { p.bits(2)});
 
-        let u = Nfct(periph);
-        
-        u
+        Nfct(periph)
     }
 
     pub fn field_detected(&mut self)   -> bool {
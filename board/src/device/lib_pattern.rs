@@ -0,0 +1,245 @@
+// LED pattern engine
+//
+// Each LED plays its own declarative pattern. Patterns are expanded step by
+// step into (level, duration) pairs, so nothing has to be buffered.
+
+use crate::device::{remaining, Led, Leds, TickPlan};
+
+const LED_COUNT: usize = 4;
+const MORSE_UNIT_MS: u32 = 150;
+const MAX_STEPS_PER_TICK: usize = 32;
+// Shorter steps, e.g. `Blink` with 0 ms, would make `tick` ask for 0 ms forever
+const MIN_STEP_MS: u32 = 10;
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum Pattern {
+    Off,
+    On,
+    /// Blink `times` times, 0 blinks forever
+    Blink { times: u8, on_ms: u16, off_ms: u16 },
+    /// Double flash followed by a pause, forever
+    Heartbeat,
+    /// `n` short flashes followed by a long pause, forever
+    ErrorCode(u8),
+    /// Text in Morse code, played once; unknown characters are skipped
+    Morse(&'static str),
+}
+
+impl Pattern {
+    fn repeats(&self) -> bool {
+        match self {
+            Pattern::Blink { times, .. } => *times == 0,
+            Pattern::Heartbeat | Pattern::ErrorCode(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Step `index` of the pattern as (LED on, duration in ms),
+    /// `None` when the sequence is over
+    fn step(&self, index: usize) -> Option<(bool, u32)> {
+        match *self {
+            Pattern::Off | Pattern::On => None,
+            Pattern::Blink { times, on_ms, off_ms } => {
+                if times != 0 && index >= 2 * times as usize {
+                    return None;
+                }
+                if index % 2 == 0 { Some((true, on_ms as u32)) } else { Some((false, off_ms as u32)) }
+            },
+            Pattern::Heartbeat => {
+                const STEPS: [(bool, u32); 4] = [(true, 100), (false, 100), (true, 100), (false, 700)];
+                STEPS.get(index).copied()
+            },
+            Pattern::ErrorCode(n) => {
+                let flashes = 2 * n as usize;
+                if index < flashes {
+                    if index % 2 == 0 { Some((true, 200)) } else { Some((false, 300)) }
+                } else if index == flashes {
+                    Some((false, 1500))
+                } else {
+                    None
+                }
+            },
+            Pattern::Morse(text) => morse_step(text, index),
+        }
+    }
+}
+
+
+// Dot is one unit on, dash three units, one unit between symbols,
+// three between letters and seven between words
+fn morse_step(text: &str, index: usize) -> Option<(bool, u32)> {
+    let mut steps = text.chars().flat_map(|sign| {
+        let code = morse_code(sign);
+        let word_gap = if sign == ' ' { Some((false, 4 * MORSE_UNIT_MS)) } else { None };
+        let count = code.len();
+        code.bytes().enumerate()
+            .flat_map(move |(i, symbol)| {
+                let on = if symbol == b'-' { 3 } else { 1 };
+                let off = if i + 1 == count { 3 } else { 1 };
+                [(true, on * MORSE_UNIT_MS), (false, off * MORSE_UNIT_MS)]
+            })
+            .chain(word_gap)
+    });
+    steps.nth(index)
+}
+
+fn morse_code(sign: char) -> &'static str {
+    match sign.to_ascii_uppercase() {
+        'A' => ".-",    'B' => "-...",  'C' => "-.-.",  'D' => "-..",
+        'E' => ".",     'F' => "..-.",  'G' => "--.",   'H' => "....",
+        'I' => "..",    'J' => ".---",  'K' => "-.-",   'L' => ".-..",
+        'M' => "--",    'N' => "-.",    'O' => "---",   'P' => ".--.",
+        'Q' => "--.-",  'R' => ".-.",   'S' => "...",   'T' => "-",
+        'U' => "..-",   'V' => "...-",  'W' => ".--",   'X' => "-..-",
+        'Y' => "-.--",  'Z' => "--..",
+        '0' => "-----", '1' => ".----", '2' => "..---", '3' => "...--",
+        '4' => "....-", '5' => ".....", '6' => "-....", '7' => "--...",
+        '8' => "---..", '9' => "----.",
+        _ => "",
+    }
+}
+
+
+#[derive(Clone, Copy)]
+struct Player {
+    pattern: Pattern,
+    step: usize,
+    // Time when current step ends, `None` when LED holds its state
+    until: Option<u32>,
+    // LED state has to be written on next tick
+    dirty: bool,
+    level: bool,
+}
+
+impl Player {
+    fn new() -> Self {
+        Player { pattern: Pattern::Off, step: 0, until: None, dirty: false, level: false }
+    }
+
+    fn start(&mut self, pattern: Pattern, now: u32) {
+        self.pattern = pattern;
+        self.step = 0;
+        self.dirty = true;
+        self.enter(now);
+    }
+
+    fn enter(&mut self, now: u32) {
+        let mut next = self.pattern.step(self.step);
+        if next.is_none() && self.pattern.repeats() && self.step != 0 {
+            self.step = 0;
+            next = self.pattern.step(0);
+        }
+
+        match next {
+            Some((level, duration)) => {
+                self.level = level;
+                self.until = Some(now.wrapping_add(duration.max(MIN_STEP_MS)));
+            },
+            None => {
+                // Sequence is over, hold the final state
+                self.level = self.pattern == Pattern::On;
+                self.until = None;
+            },
+        }
+    }
+
+    fn update(&mut self, now: u32, led: &mut Led) {
+        // Bounded, a repeating pattern made of zero length steps would never end
+        for _ in 0..MAX_STEPS_PER_TICK {
+            let until = match self.until {
+                Some(until) if remaining(until, now) == 0 => until,
+                _ => break,
+            };
+            self.step += 1;
+            self.dirty = true;
+            // Continue from the planned time, so sequences don't drift
+            self.enter(until);
+        }
+
+        if self.dirty {
+            self.dirty = false;
+            if self.level { led.on() } else { led.off() }
+        }
+    }
+}
+
+/// Plays one pattern on each of the four LEDs
+pub struct LedPatterns {
+    players: [Player; LED_COUNT],
+    step: TickPlan,
+}
+
+impl LedPatterns {
+    pub fn new() -> Self {
+        LedPatterns { players: [Player::new(); LED_COUNT], step: TickPlan::new() }
+    }
+
+    /// Start `pattern` on LED 1..=4, takes effect on next `tick` or `run`
+    pub fn play(&mut self, led: u8, pattern: Pattern, now: u32) {
+        if let Some(player) = self.players.get_mut((led as usize).wrapping_sub(1)) {
+            player.start(pattern, now);
+        }
+    }
+
+    /// Pattern currently assigned to LED 1..=4
+    pub fn pattern(&self, led: u8) -> Option<Pattern> {
+        self.players.get((led as usize).wrapping_sub(1)).map(|player| player.pattern)
+    }
+
+    /// Drive the LEDs; returns milliseconds to the next step,
+    /// `None` if all LEDs hold their state
+    pub fn tick(&mut self, now: u32, leds: &mut Leds) -> Option<u32> {
+        let leds = [&mut leds._1, &mut leds._2, &mut leds._3, &mut leds._4];
+        for (player, led) in self.players.iter_mut().zip(leds) {
+            player.update(now, led);
+        }
+
+        self.players.iter()
+            .filter_map(|player| player.until)
+            .map(|until| remaining(until, now))
+            .min()
+    }
+
+    /// Body of the pattern task: `fired` is the deadline a timed run was spawned
+    /// for, `None` right after `play`. Stale timed runs do nothing; otherwise
+    /// the LEDs are driven and `schedule(left, deadline)` spawns the next step,
+    /// see `TickPlan`.
+    pub fn run(&mut self, now: u32, leds: &mut Leds, fired: Option<u32>,
+        schedule: impl FnOnce(u32, u32) -> bool)
+    {
+        if let Some(deadline) = fired {
+            if !self.step.fired(deadline) {
+                return;
+            }
+        }
+        let left = self.tick(now, leds);
+        self.step.plan(now, left, schedule);
+    }
+}
+
+impl Default for LedPatterns {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blink_alternates_and_ends() {
+        let blink = Pattern::Blink { times: 2, on_ms: 50, off_ms: 100 };
+        let steps: Vec<_> = (0..).map_while(|index| blink.step(index)).collect();
+        assert_eq!(steps, [(true, 50), (false, 100), (true, 50), (false, 100)]);
+    }
+
+    #[test]
+    fn zero_length_steps_are_stretched() {
+        let mut player = Player::new();
+        player.start(Pattern::Blink { times: 0, on_ms: 0, off_ms: 0 }, 1_000);
+        assert_eq!(player.until, Some(1_000 + MIN_STEP_MS));
+    }
+}
@@ -0,0 +1,300 @@
+// System ON idle and System OFF
+//
+// System ON: CPU waits for event, peripherals and RAM stay as they are.
+// Parking drivers with `LowPower::prepare_for_sleep` first stops UARTE/TWIM
+// from holding HFCLK and leaking current.
+//
+// System OFF: everything but the wake sources is off, wakeup is a reset
+// (RESETREAS tells which source). Only retained RAM sections keep content.
+// GPIO SENSE of every other pin is cleared first, e.g. buttons armed for the
+// GPIOTE PORT event would wake the chip as well.
+//
+// RESETREAS is cumulative until cleared. The builder reads it at boot and
+// clears it only once the board is up, so a failed build leaves the reason
+// for the next boot; `Device` keeps what was read.
+
+use crate::hal_main as hal;
+use crate::device::Button;
+pub use hal::pac::{LPCOMP, POWER};
+
+use hal::pac::{NFCT, P0, P1};
+
+/// RAM0..=7 have 2 sections of 4 kB, RAM8 has 6 of 32 kB
+pub const RAM_BLOCKS: usize = 9;
+// P1 has only pins 0..=15
+const P0_PINS: usize = 32;
+const P1_PINS: usize = 16;
+
+
+// RESETREAS bits and their names
+const RESET_REASONS: [(u32, &str); 9] = [
+    (1 << 0, "pin reset"),
+    (1 << 1, "watchdog"),
+    (1 << 2, "soft reset"),
+    (1 << 3, "lockup"),
+    (1 << 16, "System OFF wake by GPIO"),
+    (1 << 17, "System OFF wake by LPCOMP"),
+    (1 << 18, "System OFF wake by debug"),
+    (1 << 19, "System OFF wake by NFC"),
+    (1 << 20, "System OFF wake by VBUS"),
+];
+const SYSTEM_OFF_WAKE: u32 = 0x1F << 16;
+
+
+/// Why the chip restarted, raw RESETREAS bits, none set is power-on or brownout
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ResetReason(pub u32);
+
+impl ResetReason {
+    pub fn is_power_on(&self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn is_pin_reset(&self) -> bool {
+        self.0 & 1 << 0 != 0
+    }
+
+    pub fn is_watchdog(&self) -> bool {
+        self.0 & 1 << 1 != 0
+    }
+
+    /// `SCB::sys_reset`, e.g. by a bootloader or a watchdog handler
+    pub fn is_soft_reset(&self) -> bool {
+        self.0 & 1 << 2 != 0
+    }
+
+    /// CPU locked up, e.g. fault inside the HardFault handler
+    pub fn is_lockup(&self) -> bool {
+        self.0 & 1 << 3 != 0
+    }
+
+    /// Woken from System OFF by any source
+    pub fn is_system_off_wake(&self) -> bool {
+        self.0 & SYSTEM_OFF_WAKE != 0
+    }
+
+    /// Names of the set bits
+    pub fn names(&self) -> impl Iterator<Item = &'static str> + '_ {
+        RESET_REASONS.iter()
+            .filter(|(bit, _)| self.0 & bit != 0)
+            .map(|(_, name)| *name)
+    }
+
+    pub fn write_report<W: core::fmt::Write>(&self, out: &mut W) -> core::fmt::Result {
+        write!(out, "Reset reason: ")?;
+        if self.is_power_on() {
+            write!(out, "power-on")?;
+        }
+        for (index, name) in self.names().enumerate() {
+            if index > 0 {
+                write!(out, ", ")?;
+            }
+            write!(out, "{}", name)?;
+        }
+        write!(out, "\r\n")
+    }
+}
+
+impl defmt::Format for ResetReason {
+    fn format(&self, f: defmt::Formatter) {
+        if self.is_power_on() {
+            defmt::write!(f, "power-on");
+        }
+        for (index, name) in self.names().enumerate() {
+            if index > 0 {
+                defmt::write!(f, ", ");
+            }
+            defmt::write!(f, "{=str}", name);
+        }
+    }
+}
+
+
+/// Driver that can be parked for sleep
+pub trait LowPower {
+    /// Stop transfers and disable the peripheral
+    fn prepare_for_sleep(&mut self);
+    /// Enable the peripheral again after System ON sleep
+    fn resume(&mut self) {}
+}
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum WakeLevel {
+    Low,
+    High,
+}
+
+impl WakeLevel {
+    fn other(self) -> Self {
+        match self {
+            WakeLevel::Low => WakeLevel::High,
+            WakeLevel::High => WakeLevel::Low,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum LpcompDetect {
+    Cross,
+    Up,
+    Down,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum WakeSource {
+    /// GPIO DETECT, pin is `port * 32 + pin`; a pin already at the level
+    /// (e.g. button still held) wakes on the other one instead of at once
+    Pin(u8, WakeLevel),
+    /// NFC field
+    Nfc,
+    /// Analog input 0..=7 crossing `reference_eighths`/8 of VDD (1..=7)
+    Lpcomp { input: u8, reference_eighths: u8, detect: LpcompDetect },
+}
+
+impl WakeSource {
+    /// Button pushed, buttons pull up and short to GND
+    pub fn button(button: &Button) -> Self {
+        WakeSource::Pin(button.inner.psel_bits() as u8, WakeLevel::Low)
+    }
+}
+
+
+pub struct Power {
+    power: POWER,
+    lpcomp: LPCOMP,
+}
+
+impl Power {
+    pub fn new(power: POWER, lpcomp: LPCOMP) -> Self {
+        Power { power, lpcomp }
+    }
+
+    pub fn free(self) -> (POWER, LPCOMP) {
+        (self.power, self.lpcomp)
+    }
+
+    /// RESETREAS as it is, includes earlier resets until cleared
+    pub fn reset_reason(&self) -> ResetReason {
+        ResetReason(self.power.resetreas.read().bits())
+    }
+
+    /// Clear `reason` from RESETREAS, later resets would add to it otherwise
+    pub fn clear_reset_reason(&mut self, reason: ResetReason) {
+        // Bits are cleared by writing 1
+        self.power.resetreas.write(|w| unsafe { w.bits(reason.0) });
+    }
+
+    /// Low power sub mode of System ON, wakeup latency varies with what is on
+    pub fn low_power_mode(&mut self) {
+        self.power.tasks_lowpwr.write(|w| unsafe { w.bits(1) });
+    }
+
+    /// Constant wakeup latency at the price of current
+    pub fn constant_latency_mode(&mut self) {
+        self.power.tasks_constlat.write(|w| unsafe { w.bits(1) });
+    }
+
+    /// Sections of RAM `block` kept in System OFF, bit 0 is section 0
+    pub fn set_ram_retention(&mut self, block: usize, sections: u16) {
+        let power = match block {
+            0 => &self.power.ram0.power,
+            1 => &self.power.ram1.power,
+            2 => &self.power.ram2.power,
+            3 => &self.power.ram3.power,
+            4 => &self.power.ram4.power,
+            5 => &self.power.ram5.power,
+            6 => &self.power.ram6.power,
+            7 => &self.power.ram7.power,
+            8 => &self.power.ram8.power,
+            _ => return,
+        };
+        // Upper half are the S0..S15 RETENTION bits
+        power.modify(|r, w| unsafe { w.bits(r.bits() & 0xFFFF | (sections as u32) << 16) });
+    }
+
+    /// Keep whole RAM in System OFF, costs about 30 nA per section
+    pub fn retain_all_ram(&mut self) {
+        for block in 0..RAM_BLOCKS {
+            self.set_ram_retention(block, 0xFFFF);
+        }
+    }
+
+    /// Park every driver in `parts`
+    pub fn prepare_for_sleep(&mut self, parts: &mut [&mut dyn LowPower]) {
+        for part in parts.iter_mut() {
+            part.prepare_for_sleep();
+        }
+    }
+
+    pub fn resume(&mut self, parts: &mut [&mut dyn LowPower]) {
+        for part in parts.iter_mut() {
+            part.resume();
+        }
+    }
+
+    /// Park `parts`, arm `wakes` and enter System OFF. Wakeup resets the chip.
+    pub fn system_off(&mut self, wakes: &[WakeSource], parts: &mut [&mut dyn LowPower]) -> ! {
+        self.prepare_for_sleep(parts);
+        // Only wake pins may sense
+        let (p0, p1) = unsafe { (&*P0::ptr(), &*P1::ptr()) };
+        for pin_cnf in p0.pin_cnf.iter().take(P0_PINS).chain(p1.pin_cnf.iter().take(P1_PINS)) {
+            pin_cnf.modify(|_, w| w.sense().disabled());
+        }
+        for wake in wakes {
+            self.arm(*wake);
+        }
+
+        self.power.systemoff.write(|w| w.systemoff().enter());
+        // Under debugger System OFF is emulated and the CPU keeps running
+        loop {
+            cortex_m::asm::wfe();
+        }
+    }
+
+    fn arm(&mut self, wake: WakeSource) {
+        match wake {
+            WakeSource::Pin(id, level) => {
+                // Only SENSE is changed, pin stays with its driver
+                let port = if id < 32 { unsafe { &*P0::ptr() } } else { unsafe { &*P1::ptr() } };
+                let pin = (id % 32) as usize;
+                let high = port.in_.read().bits() & 1 << pin != 0;
+                // Sensing the level the pin is at would wake immediately
+                let level = if high == (level == WakeLevel::High) { level.other() } else { level };
+                port.pin_cnf[pin].modify(|_, w| match level {
+                    WakeLevel::Low => w.sense().low(),
+                    WakeLevel::High => w.sense().high(),
+                });
+            },
+            WakeSource::Nfc => {
+                // Sense mode wakes on field, NFCT itself may be owned by `Nfct`
+                let nfct = unsafe { &*NFCT::ptr() };
+                nfct.tasks_sense.write(|w| unsafe { w.bits(1) });
+            },
+            WakeSource::Lpcomp { input, reference_eighths, detect } => {
+                let lpcomp = &self.lpcomp;
+                lpcomp.enable.write(|w| w.enable().disabled());
+                lpcomp.psel.write(|w| unsafe { w.bits(input.min(7) as u32) });
+                // REF1_8VDD is 0, REF7_8VDD is 6
+                lpcomp.refsel.write(|w| unsafe { w.bits(reference_eighths.clamp(1, 7) as u32 - 1) });
+                lpcomp.anadetect.write(|w| match detect {
+                    LpcompDetect::Cross => w.anadetect().cross(),
+                    LpcompDetect::Up => w.anadetect().up(),
+                    LpcompDetect::Down => w.anadetect().down(),
+                });
+                lpcomp.enable.write(|w| w.enable().enabled());
+                lpcomp.tasks_start.write(|w| unsafe { w.bits(1) });
+            },
+        }
+    }
+}
+
+
+/// System ON idle: sleep until an interrupt or event, use in `#[idle]`
+pub fn system_on_idle() {
+    // Set the event register so the first WFE clears it right away,
+    // the second one really sleeps
+    cortex_m::asm::sev();
+    cortex_m::asm::wfe();
+    cortex_m::asm::wfe();
+}
@@ -0,0 +1,382 @@
+// PPI: hardware event to task links without the CPU
+//
+// nRF52840 has PPI (DPPI is nRF53/nRF91): 20 programmable channels, each with
+// one event, one task and an optional fork task, and 6 channel groups that are
+// switched on/off together by their own tasks. Channels and groups go back to
+// the pool when their handle is dropped. Handles outlive no `Ppi`: only one
+// exists at a time and it's given back only once every handle is dropped.
+//
+// Endpoints are register addresses. RTC events are only routed to PPI with
+// EVTEN set, their constructors do that.
+
+use crate::hal_main as hal;
+pub use hal::pac::{PPI, SAADC};
+
+use hal::pac::{rtc0, timer0, uarte0, GPIOTE};
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+pub const PPI_CHANNELS: usize = 20;
+pub const PPI_GROUPS: usize = 6;
+
+// Allocated channels and groups, bit per index
+static CHANNELS_USED: AtomicU32 = AtomicU32::new(0);
+static GROUPS_USED: AtomicU32 = AtomicU32::new(0);
+// A `Ppi` exists, its allocator state is live
+static TAKEN: AtomicBool = AtomicBool::new(false);
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum PpiError {
+    NoFreeChannel,
+    NoFreeGroup,
+}
+
+
+/// Address of an EVENTS_* register
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub struct Event(u32);
+
+/// Address of a TASKS_* register
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub struct Task(u32);
+
+fn address<T>(register: &T) -> u32 {
+    register as *const T as u32
+}
+
+fn gpiote() -> &'static hal::pac::gpiote::RegisterBlock {
+    // Only addresses are taken, nothing is written
+    unsafe { &*GPIOTE::ptr() }
+}
+
+fn saadc() -> &'static hal::pac::saadc::RegisterBlock {
+    unsafe { &*SAADC::ptr() }
+}
+
+impl Event {
+    /// Any EVENTS_* register not covered below
+    ///
+    /// # Safety
+    /// `address` has to be an event register
+    pub unsafe fn from_address(address: u32) -> Self {
+        Event(address)
+    }
+
+    pub fn address(&self) -> u32 {
+        self.0
+    }
+
+    /// GPIOTE channel 0..=7 configured as event, see `GpioteManager`
+    pub fn gpiote_in(channel: usize) -> Self {
+        Event(address(&gpiote().events_in[channel]))
+    }
+
+    pub fn gpiote_port() -> Self {
+        Event(address(&gpiote().events_port))
+    }
+
+    pub fn timer_compare(timer: &timer0::RegisterBlock, channel: usize) -> Self {
+        Event(address(&timer.events_compare[channel]))
+    }
+
+    pub fn rtc_tick(rtc: &rtc0::RegisterBlock) -> Self {
+        rtc.evtenset.write(|w| w.tick().set());
+        Event(address(&rtc.events_tick))
+    }
+
+    pub fn rtc_overflow(rtc: &rtc0::RegisterBlock) -> Self {
+        rtc.evtenset.write(|w| w.ovrflw().set());
+        Event(address(&rtc.events_ovrflw))
+    }
+
+    /// Compare 0..=3, compare 0 of `RtcMonotonic` is taken
+    pub fn rtc_compare(rtc: &rtc0::RegisterBlock, channel: usize) -> Self {
+        rtc.evtenset.write(|w| unsafe { w.bits(1 << (16 + channel)) });
+        Event(address(&rtc.events_compare[channel]))
+    }
+
+    /// Byte received
+    pub fn uarte_rxdrdy(uarte: &uarte0::RegisterBlock) -> Self {
+        Event(address(&uarte.events_rxdrdy))
+    }
+
+    /// Receive buffer full
+    pub fn uarte_endrx(uarte: &uarte0::RegisterBlock) -> Self {
+        Event(address(&uarte.events_endrx))
+    }
+
+    /// Receiver stopped after STOPRX
+    pub fn uarte_rxto(uarte: &uarte0::RegisterBlock) -> Self {
+        Event(address(&uarte.events_rxto))
+    }
+
+    pub fn uarte_endtx(uarte: &uarte0::RegisterBlock) -> Self {
+        Event(address(&uarte.events_endtx))
+    }
+
+    pub fn saadc_started() -> Self {
+        Event(address(&saadc().events_started))
+    }
+
+    /// Result buffer full
+    pub fn saadc_end() -> Self {
+        Event(address(&saadc().events_end))
+    }
+
+    /// One sample written to the result buffer
+    pub fn saadc_resultdone() -> Self {
+        Event(address(&saadc().events_resultdone))
+    }
+}
+
+impl Task {
+    /// Any TASKS_* register not covered below
+    ///
+    /// # Safety
+    /// `address` has to be a task register
+    pub unsafe fn from_address(address: u32) -> Self {
+        Task(address)
+    }
+
+    pub fn address(&self) -> u32 {
+        self.0
+    }
+
+    /// Channel 0..=7 configured as task output
+    pub fn gpiote_out(channel: usize) -> Self {
+        Task(address(&gpiote().tasks_out[channel]))
+    }
+
+    pub fn gpiote_set(channel: usize) -> Self {
+        Task(address(&gpiote().tasks_set[channel]))
+    }
+
+    pub fn gpiote_clr(channel: usize) -> Self {
+        Task(address(&gpiote().tasks_clr[channel]))
+    }
+
+    pub fn timer_start(timer: &timer0::RegisterBlock) -> Self {
+        Task(address(&timer.tasks_start))
+    }
+
+    pub fn timer_stop(timer: &timer0::RegisterBlock) -> Self {
+        Task(address(&timer.tasks_stop))
+    }
+
+    pub fn timer_clear(timer: &timer0::RegisterBlock) -> Self {
+        Task(address(&timer.tasks_clear))
+    }
+
+    /// Increment in counter mode
+    pub fn timer_count(timer: &timer0::RegisterBlock) -> Self {
+        Task(address(&timer.tasks_count))
+    }
+
+    pub fn timer_capture(timer: &timer0::RegisterBlock, channel: usize) -> Self {
+        Task(address(&timer.tasks_capture[channel]))
+    }
+
+    pub fn rtc_start(rtc: &rtc0::RegisterBlock) -> Self {
+        Task(address(&rtc.tasks_start))
+    }
+
+    pub fn rtc_stop(rtc: &rtc0::RegisterBlock) -> Self {
+        Task(address(&rtc.tasks_stop))
+    }
+
+    pub fn rtc_clear(rtc: &rtc0::RegisterBlock) -> Self {
+        Task(address(&rtc.tasks_clear))
+    }
+
+    pub fn uarte_startrx(uarte: &uarte0::RegisterBlock) -> Self {
+        Task(address(&uarte.tasks_startrx))
+    }
+
+    /// Stop receiving, e.g. on a TIMER compare for idle line timeout
+    pub fn uarte_stoprx(uarte: &uarte0::RegisterBlock) -> Self {
+        Task(address(&uarte.tasks_stoprx))
+    }
+
+    pub fn uarte_flushrx(uarte: &uarte0::RegisterBlock) -> Self {
+        Task(address(&uarte.tasks_flushrx))
+    }
+
+    pub fn uarte_starttx(uarte: &uarte0::RegisterBlock) -> Self {
+        Task(address(&uarte.tasks_starttx))
+    }
+
+    pub fn saadc_start() -> Self {
+        Task(address(&saadc().tasks_start))
+    }
+
+    /// Take one sample on every enabled SAADC channel
+    pub fn saadc_sample() -> Self {
+        Task(address(&saadc().tasks_sample))
+    }
+
+    pub fn saadc_stop() -> Self {
+        Task(address(&saadc().tasks_stop))
+    }
+}
+
+
+/// Channel and group allocator, owns the PPI peripheral
+pub struct Ppi {
+    ppi: PPI,
+}
+
+impl Ppi {
+    /// All programmable channels off and free, `None` while another `Ppi` exists
+    pub fn new(ppi: PPI) -> Option<Self> {
+        if TAKEN.swap(true, Ordering::SeqCst) {
+            return None;
+        }
+        ppi.chenclr.write(|w| unsafe { w.bits((1 << PPI_CHANNELS) - 1) });
+        for group in ppi.chg.iter() {
+            group.reset();
+        }
+        CHANNELS_USED.store(0, Ordering::SeqCst);
+        GROUPS_USED.store(0, Ordering::SeqCst);
+        Some(Ppi { ppi })
+    }
+
+    /// Give the peripheral back, fails while any channel or group handle is alive
+    pub fn free(self) -> Result<PPI, Self> {
+        if CHANNELS_USED.load(Ordering::SeqCst) != 0 || GROUPS_USED.load(Ordering::SeqCst) != 0 {
+            return Err(self);
+        }
+        TAKEN.store(false, Ordering::SeqCst);
+        Ok(self.ppi)
+    }
+
+    /// Link `event` to `task` on a free channel, the channel starts disabled
+    pub fn connect(&mut self, event: Event, task: Task) -> Result<PpiChannel, PpiError> {
+        let index = allocate(&CHANNELS_USED, PPI_CHANNELS).ok_or(PpiError::NoFreeChannel)?;
+        let ppi = registers();
+        ppi.ch[index].eep.write(|w| unsafe { w.bits(event.0) });
+        ppi.ch[index].tep.write(|w| unsafe { w.bits(task.0) });
+        ppi.fork[index].tep.reset();
+        Ok(PpiChannel { index })
+    }
+
+    pub fn group(&mut self) -> Result<PpiGroup, PpiError> {
+        let index = allocate(&GROUPS_USED, PPI_GROUPS).ok_or(PpiError::NoFreeGroup)?;
+        registers().chg[index].reset();
+        Ok(PpiGroup { index })
+    }
+
+    pub fn free_channels(&self) -> u32 {
+        PPI_CHANNELS as u32 - CHANNELS_USED.load(Ordering::SeqCst).count_ones()
+    }
+}
+
+fn registers() -> &'static hal::pac::ppi::RegisterBlock {
+    // Handles only touch registers of their own channel or group
+    unsafe { &*PPI::ptr() }
+}
+
+fn allocate(used: &AtomicU32, count: usize) -> Option<usize> {
+    let mut taken = used.load(Ordering::SeqCst);
+    loop {
+        let index = (!taken).trailing_zeros() as usize;
+        if index >= count {
+            return None;
+        }
+        match used.compare_exchange(taken, taken | 1 << index, Ordering::SeqCst, Ordering::SeqCst) {
+            Ok(_) => return Some(index),
+            Err(now) => taken = now,
+        }
+    }
+}
+
+
+/// Allocated channel, disabled and released on drop
+pub struct PpiChannel {
+    index: usize,
+}
+
+impl PpiChannel {
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    pub fn enable(&mut self) {
+        registers().chenset.write(|w| unsafe { w.bits(1 << self.index) });
+    }
+
+    pub fn disable(&mut self) {
+        registers().chenclr.write(|w| unsafe { w.bits(1 << self.index) });
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        registers().chen.read().bits() & 1 << self.index != 0
+    }
+
+    /// Trigger a second task from the same event
+    pub fn fork(&mut self, task: Task) {
+        registers().fork[self.index].tep.write(|w| unsafe { w.bits(task.0) });
+    }
+
+    pub fn unfork(&mut self) {
+        registers().fork[self.index].tep.reset();
+    }
+}
+
+impl Drop for PpiChannel {
+    fn drop(&mut self) {
+        self.disable();
+        let ppi = registers();
+        ppi.ch[self.index].eep.reset();
+        ppi.ch[self.index].tep.reset();
+        ppi.fork[self.index].tep.reset();
+        // A reused index must not join groups this channel was in
+        for group in ppi.chg.iter() {
+            group.modify(|r, w| unsafe { w.bits(r.bits() & !(1 << self.index)) });
+        }
+        CHANNELS_USED.fetch_and(!(1 << self.index), Ordering::SeqCst);
+    }
+}
+
+
+/// Channels switched on and off together, also from other PPI channels
+pub struct PpiGroup {
+    index: usize,
+}
+
+impl PpiGroup {
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    pub fn add(&mut self, channel: &PpiChannel) {
+        registers().chg[self.index].modify(|r, w| unsafe { w.bits(r.bits() | 1 << channel.index) });
+    }
+
+    pub fn remove(&mut self, channel: &PpiChannel) {
+        registers().chg[self.index].modify(|r, w| unsafe { w.bits(r.bits() & !(1 << channel.index)) });
+    }
+
+    pub fn enable(&mut self) {
+        registers().tasks_chg[self.index].en.write(|w| unsafe { w.bits(1) });
+    }
+
+    pub fn disable(&mut self) {
+        registers().tasks_chg[self.index].dis.write(|w| unsafe { w.bits(1) });
+    }
+
+    /// Task enabling the whole group, e.g. for a one-shot chain
+    pub fn task_enable(&self) -> Task {
+        Task(address(&registers().tasks_chg[self.index].en))
+    }
+
+    pub fn task_disable(&self) -> Task {
+        Task(address(&registers().tasks_chg[self.index].dis))
+    }
+}
+
+impl Drop for PpiGroup {
+    fn drop(&mut self) {
+        registers().chg[self.index].reset();
+        GROUPS_USED.fetch_and(!(1 << self.index), Ordering::SeqCst);
+    }
+}
@@ -0,0 +1,240 @@
+use crate::hal_main as hal;
+pub use hal::pac::PWM0;
+pub use hal::pwm::Instance as PwmInstance;
+
+use crate::device::{LedPolarity, Leds, PWM_SEQ_BUF_LEN};
+use core::sync::atomic::{compiler_fence, Ordering::SeqCst};
+
+
+// 16 MHz / 16 = 1 MHz PWM clock, 256 ticks per period -> ~3.9 kHz, no flicker
+const PWM_PRESCALER_DIV_16: u32 = 4;
+const PWM_COUNTERTOP: u16 = 255;
+const PWM_PERIOD_US: u32 = 256;
+// Polarity bit of a sequence value: set (FallingEdge) drives the output high
+// for `duty` ticks (active high LEDs), clear (RisingEdge) drives it low (active low)
+const PWM_FALLING_EDGE: u16 = 0x8000;
+
+const CHANNELS: usize = 4;
+const MAX_STEPS: usize = PWM_SEQ_BUF_LEN / CHANNELS;
+
+
+/// Board LEDs driven by PWM sequence DMA
+///
+/// All four channels share one sequence, so only one LED can be animated at
+/// a time; starting an animation or changing brightness stops the running one
+/// and the other LEDs keep their static brightness.
+pub struct PwmLeds<T: PwmInstance> {
+    pwm: T,
+    leds: Leds,
+    seq_buffor: u32,
+    level: [u8; CHANNELS],
+    running: bool,
+}
+
+impl<T> PwmLeds<T>
+where
+    T: PwmInstance,
+{
+    /// Connect `leds` to PWM channels 0..=3, `seq_buffor` is the RAM address
+    /// of `PWM_SEQ_BUF_LEN` u16 values (`PWM_SEQ_BUF_DEF`)
+    pub fn new(pwm: T, leds: Leds, seq_buffor: u32) -> Self {
+        let pins = [&leds._1, &leds._2, &leds._3, &leds._4];
+        for (out, led) in pwm.psel.out.iter().zip(pins) {
+            out.write(|w| unsafe { w.bits(led.inner.psel_bits()) });
+        }
+
+        pwm.mode.write(|w| w.updown().up());
+        pwm.prescaler.write(|w| unsafe { w.bits(PWM_PRESCALER_DIV_16) });
+        pwm.countertop.write(|w| unsafe { w.countertop().bits(PWM_COUNTERTOP) });
+        pwm.decoder.write(|w| {
+            w.load().individual();
+            w.mode().refresh_count()
+        });
+        pwm.enable.write(|w| w.enable().enabled());
+
+        let mut u = PwmLeds { pwm, leds, seq_buffor, level: [0; CHANNELS], running: false };
+        u.apply();
+        u
+    }
+
+    /// Stop PWM and give the LEDs back as GPIO
+    pub fn free(mut self) -> (T, Leds) {
+        self.stop_sequence();
+        self.pwm.enable.write(|w| w.enable().disabled());
+        for out in self.pwm.psel.out.iter() {
+            out.write(|w| w.connect().disconnected());
+        }
+        (self.pwm, self.leds)
+    }
+
+    /// Turns on LED 1..=4 at full brightness
+    pub fn on(&mut self, led: u8) {
+        self.set_brightness(led, u8::MAX);
+    }
+
+    /// Turns off LED 1..=4
+    pub fn off(&mut self, led: u8) {
+        self.set_brightness(led, 0);
+    }
+
+    pub fn toggle(&mut self, led: u8) {
+        if self.is_on(led) {
+            self.off(led);
+        } else {
+            self.on(led);
+        }
+    }
+
+    /// Returns `true` if LED glows at any brightness
+    pub fn is_on(&self, led: u8) -> bool {
+        self.brightness(led) != 0
+    }
+
+    /// Last brightness set for LED 1..=4, target one during a fade
+    pub fn brightness(&self, led: u8) -> u8 {
+        channel(led).map_or(0, |ch| self.level[ch])
+    }
+
+    pub fn set_brightness(&mut self, led: u8, value: u8) {
+        if let Some(ch) = channel(led) {
+            self.level[ch] = value;
+            self.apply();
+        }
+    }
+
+    /// Fade LED 1..=4 from current brightness to `to` within `duration_ms`
+    pub fn fade(&mut self, led: u8, to: u8, duration_ms: u32) {
+        let ch = match channel(led) {
+            Some(ch) => ch,
+            None => return,
+        };
+        let from = self.level[ch];
+        self.level[ch] = to;
+
+        self.stop_sequence();
+        let steps = self.write_ramp(0, ch, from, to, MAX_STEPS);
+        self.play(steps, refresh_periods(duration_ms, steps), None);
+    }
+
+    pub fn fade_in(&mut self, led: u8, duration_ms: u32) {
+        self.fade(led, u8::MAX, duration_ms);
+    }
+
+    pub fn fade_out(&mut self, led: u8, duration_ms: u32) {
+        self.fade(led, 0, duration_ms);
+    }
+
+    /// Breathe LED 1..=4 between off and full brightness until stopped
+    pub fn breathe(&mut self, led: u8, period_ms: u32) {
+        let ch = match channel(led) {
+            Some(ch) => ch,
+            None => return,
+        };
+        self.level[ch] = u8::MAX;
+
+        // Rising half in SEQ0, falling half in SEQ1, hardware loops them forever
+        self.stop_sequence();
+        let half = MAX_STEPS / 2;
+        let steps = self.write_ramp(0, ch, 0, u8::MAX, half);
+        self.write_ramp(steps, ch, u8::MAX, 0, half);
+        self.play(steps, refresh_periods(period_ms / 2, steps), Some(steps));
+    }
+
+    /// Stop animation, LEDs go back to their static brightness
+    pub fn stop_animation(&mut self) {
+        self.apply();
+    }
+
+    // Play static brightness of all channels as one step sequence
+    fn apply(&mut self) {
+        self.stop_sequence();
+        for ch in 0..CHANNELS {
+            self.write_value(ch, self.level[ch]);
+        }
+        self.play(1, 0, None);
+    }
+
+    // Ramp of `count` steps starting at step `first`, other channels hold their level
+    fn write_ramp(&mut self, first: usize, ch: usize, from: u8, to: u8, count: usize) -> usize {
+        let last = count.max(2) - 1;
+        for step in 0..count {
+            let value = from as i32 + (to as i32 - from as i32) * step as i32 / last as i32;
+            for other in 0..CHANNELS {
+                let level = if other == ch { value as u8 } else { self.level[other] };
+                self.write_value((first + step) * CHANNELS + other, level);
+            }
+        }
+        count
+    }
+
+    fn write_value(&mut self, index: usize, level: u8) {
+        // Square brightness, eye perceives linear duty as too bright at low end
+        let duty = (level as u16 * level as u16) / PWM_COUNTERTOP;
+        let led = [&self.leds._1, &self.leds._2, &self.leds._3, &self.leds._4][index % CHANNELS];
+        let polarity = match led.polarity {
+            LedPolarity::ActiveLow => 0,
+            LedPolarity::ActiveHigh => PWM_FALLING_EDGE,
+        };
+        unsafe {
+            core::ptr::write_volatile((self.seq_buffor as *mut u16).add(index), duty | polarity);
+        }
+    }
+
+    // Start SEQ0 with `steps` steps, optionally looped forever with SEQ1 of `loop_steps`
+    fn play(&mut self, steps: usize, refresh: u32, loop_steps: Option<usize>) {
+        let values = (steps * CHANNELS) as u32;
+        self.pwm.seq0.ptr.write(|w| unsafe { w.bits(self.seq_buffor) });
+        self.pwm.seq0.cnt.write(|w| unsafe { w.bits(values) });
+        self.pwm.seq0.refresh.write(|w| unsafe { w.bits(refresh) });
+        self.pwm.seq0.enddelay.write(|w| unsafe { w.bits(0) });
+
+        match loop_steps {
+            Some(second) => {
+                let offset = values * core::mem::size_of::<u16>() as u32;
+                self.pwm.seq1.ptr.write(|w| unsafe { w.bits(self.seq_buffor + offset) });
+                self.pwm.seq1.cnt.write(|w| unsafe { w.bits((second * CHANNELS) as u32) });
+                self.pwm.seq1.refresh.write(|w| unsafe { w.bits(refresh) });
+                self.pwm.seq1.enddelay.write(|w| unsafe { w.bits(0) });
+                self.pwm.loop_.write(|w| unsafe { w.cnt().bits(1) });
+                self.pwm.shorts.write(|w| w.loopsdone_seqstart0().enabled());
+            },
+            None => {
+                self.pwm.loop_.write(|w| w.cnt().disabled());
+                self.pwm.shorts.reset();
+            },
+        }
+
+        // Make sure sequence is in RAM before DMA reads it
+        compiler_fence(SeqCst);
+        self.pwm.events_seqstarted[0].reset();
+        self.pwm.tasks_seqstart[0].write(|w| unsafe { w.bits(1) });
+        while self.pwm.events_seqstarted[0].read().bits() == 0 {}
+        self.running = true;
+    }
+
+    fn stop_sequence(&mut self) {
+        // Loop short would restart the sequence right after stop
+        self.pwm.shorts.reset();
+        if !self.running {
+            return;
+        }
+        self.running = false;
+        self.pwm.events_stopped.reset();
+        self.pwm.tasks_stop.write(|w| unsafe { w.bits(1) });
+        while self.pwm.events_stopped.read().bits() == 0 {}
+        compiler_fence(SeqCst);
+    }
+}
+
+fn channel(led: u8) -> Option<usize> {
+    match led {
+        1..=4 => Some(led as usize - 1),
+        _ => None,
+    }
+}
+
+// Extra PWM periods each step is held, so `steps` steps take `duration_ms`
+fn refresh_periods(duration_ms: u32, steps: usize) -> u32 {
+    let periods = duration_ms.saturating_mul(1000) / PWM_PERIOD_US / steps.max(1) as u32;
+    periods.saturating_sub(1).min(0x00FF_FFFF)
+}
@@ -0,0 +1,345 @@
+use crate::hal_main as hal;
+use crate::device::{LowPower, RAM};
+pub use hal::spim::{self, Frequency, Mode, MODE_0, MODE_1, MODE_2, MODE_3};
+pub use hal::pac::{SPIM2, SPIM3};
+
+use hal::gpio::{Pin, Output, PushPull};
+pub use embedded_hal::blocking::spi::{Operation, Transactional};
+use embedded_hal::blocking::spi::{Transfer, Write};
+use embedded_hal::digital::v2::OutputPin;
+use core::sync::atomic::{compiler_fence, Ordering::SeqCst};
+
+// Anomaly 198: SPIM3 sends corrupted data while the CPU or another EasyDMA
+// accesses the RAM block of the TX buffer. Errata workaround: this register
+// holds a mask of RAM blocks (RAM0..=7 8 kB each, RAM8 bit 8) reserved for
+// SPIM3 during the transfer.
+const ANOMALY_198_BLOCKS: *mut u32 = 0x4000_0E00 as *mut u32;
+const RAM_BLOCK_SIZE: u32 = 0x2000;
+const RAM8_BLOCK: u32 = 8;
+
+const CYCLES_PER_US: u32 = 64;
+// One byte at the slowest 125 kHz clock, transfer timeout doesn't depend on
+// the set frequency
+const BYTE_US_MAX: u32 = 64;
+const TIMEOUT_MARGIN_US: u32 = 1_000;
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum SpiError {
+    TxBufferTooLong,
+    RxBufferTooLong,
+    /// END didn't come within the time the longest buffer takes at 125 kHz
+    Timeout,
+}
+
+
+/// SPIM with EasyDMA buffers given as RAM addresses, chip select is
+/// driven by the device driver
+pub struct Spim<T>(T);
+
+impl<T> Spim<T>
+where
+    T: spim::Instance,
+{
+    /// `orc` is clocked out once `tx_len` bytes are sent
+    pub fn new(spim: T, pins: spim::Pins, frequency: Frequency, mode: Mode, orc: u8) -> Self {
+        spim.psel.sck.write(|w| unsafe { w.bits(pins.sck.psel_bits()) });
+        match pins.mosi {
+            Some(mosi) => spim.psel.mosi.write(|w| unsafe { w.bits(mosi.psel_bits()) }),
+            None => spim.psel.mosi.write(|w| w.connect().disconnected()),
+        }
+        match pins.miso {
+            Some(miso) => spim.psel.miso.write(|w| unsafe { w.bits(miso.psel_bits()) }),
+            None => spim.psel.miso.write(|w| w.connect().disconnected()),
+        }
+
+        let spim = Spim(spim);
+        spim.0.enable.write(|w| w.enable().enabled());
+        spim.0.frequency.write(|w| w.frequency().variant(frequency));
+        spim.0.orc.write(|w| unsafe { w.orc().bits(orc) });
+        spim.set_mode(mode);
+        spim
+    }
+
+    pub fn set_mode(&self, mode: Mode) {
+        self.0.config.write(|w| {
+            let w = w.order().msb_first();
+            let w = match mode.polarity {
+                spim::Polarity::IdleLow => w.cpol().active_high(),
+                spim::Polarity::IdleHigh => w.cpol().active_low(),
+            };
+            match mode.phase {
+                spim::Phase::CaptureOnFirstTransition => w.cpha().leading(),
+                spim::Phase::CaptureOnSecondTransition => w.cpha().trailing(),
+            }
+        });
+    }
+
+    pub fn set_frequency(&self, frequency: Frequency) {
+        self.0.frequency.write(|w| w.frequency().variant(frequency));
+    }
+
+    /// Full duplex DMA transfer, blocks until both buffers are done or the
+    /// transfer times out; `tx_len` or `rx_len` may be 0
+    pub fn transfer(&mut self, tx_buffor: u32, tx_len: usize, rx_buffor: u32, rx_len: usize)
+        -> Result<(), SpiError>
+    {
+        if tx_len > u16::MAX as usize {
+            return Err(SpiError::TxBufferTooLong);
+        }
+        if rx_len > u16::MAX as usize {
+            return Err(SpiError::RxBufferTooLong);
+        }
+
+        // Only SPIM3 is affected by anomaly 198
+        let anomaly_198 = core::ptr::eq(&*self.0, SPIM3::ptr());
+        let preserved = if anomaly_198 {
+            let preserved = unsafe { core::ptr::read_volatile(ANOMALY_198_BLOCKS) };
+            unsafe { core::ptr::write_volatile(ANOMALY_198_BLOCKS, ram_blocks(tx_buffor, tx_len)) };
+            Some(preserved)
+        } else {
+            None
+        };
+
+        compiler_fence(SeqCst);
+
+        self.0.txd.ptr.write(|w| unsafe { w.ptr().bits(tx_buffor) });
+        self.0.txd.maxcnt.write(|w| unsafe { w.maxcnt().bits(tx_len as _) });
+        self.0.rxd.ptr.write(|w| unsafe { w.ptr().bits(rx_buffor) });
+        self.0.rxd.maxcnt.write(|w| unsafe { w.maxcnt().bits(rx_len as _) });
+
+        self.0.events_end.reset();
+        self.0.tasks_start.write(|w| unsafe { w.bits(1) });
+        let finished = self.wait_end(transfer_timeout_us(tx_len, rx_len));
+        self.0.events_end.reset();
+
+        compiler_fence(SeqCst);
+        if let Some(preserved) = preserved {
+            unsafe { core::ptr::write_volatile(ANOMALY_198_BLOCKS, preserved) };
+        }
+        if finished {
+            Ok(())
+        } else {
+            Err(SpiError::Timeout)
+        }
+    }
+
+    // Wait for END, on timeout the transfer is stopped,
+    // returns false when the transfer timed out
+    fn wait_end(&mut self, timeout_us: u32) -> bool {
+        let mut left = timeout_us;
+        while self.0.events_end.read().bits() == 0 {
+            if left == 0 {
+                self.0.events_stopped.reset();
+                self.0.tasks_stop.write(|w| unsafe { w.bits(1) });
+                let mut left = TIMEOUT_MARGIN_US;
+                while self.0.events_stopped.read().bits() == 0 && left > 0 {
+                    left -= 1;
+                    cortex_m::asm::delay(CYCLES_PER_US);
+                }
+                return false;
+            }
+            left -= 1;
+            cortex_m::asm::delay(CYCLES_PER_US);
+        }
+        true
+    }
+
+    pub fn free(self) -> T {
+        self.0.enable.write(|w| w.enable().disabled());
+        self.0
+    }
+}
+
+// Longest time a transfer may take, whatever the bus frequency
+fn transfer_timeout_us(tx_len: usize, rx_len: usize) -> u32 {
+    tx_len.max(rx_len) as u32 * BYTE_US_MAX + TIMEOUT_MARGIN_US
+}
+
+// Mask of RAM blocks `len` bytes at `buffor` lie in
+fn ram_blocks(buffor: u32, len: usize) -> u32 {
+    if len == 0 {
+        return 0;
+    }
+    let block = |address: u32| (address.wrapping_sub(RAM) / RAM_BLOCK_SIZE).min(RAM8_BLOCK);
+    let last = buffor.wrapping_add(len as u32 - 1);
+    (block(buffor)..=block(last)).fold(0, |mask, index| mask | 1 << index)
+}
+
+impl<T: spim::Instance> LowPower for Spim<T> {
+    fn prepare_for_sleep(&mut self) {
+        self.0.enable.write(|w| w.enable().disabled());
+    }
+
+    fn resume(&mut self) {
+        self.0.enable.write(|w| w.enable().enabled());
+    }
+}
+
+
+/// Chip selects of devices on `board_spi`
+pub struct SpiSelects {
+    pub _1: Pin<Output<PushPull>>,
+    pub _2: Pin<Output<PushPull>>,
+}
+
+
+/// Bus settings of one device, applied before each of its transactions
+#[derive(Clone, Copy, PartialEq)]
+pub struct SpiConfig {
+    pub mode: Mode,
+    pub frequency: Frequency,
+}
+
+impl Default for SpiConfig {
+    fn default() -> Self {
+        SpiConfig { mode: MODE_0, frequency: Frequency::M4 }
+    }
+}
+
+
+/// Device on a shared SPIM: own chip select and config, data goes through
+/// DMA region of the bus (`SPIM3_TX_BUF_DEF`, `SPIM3_RX_BUF_DEF`)
+pub struct SpiDevice {
+    cs: Pin<Output<PushPull>>,
+    config: SpiConfig,
+    tx_buffor: u32,
+    rx_buffor: u32,
+    buffor_len: usize,
+}
+
+impl SpiDevice {
+    pub fn new(mut cs: Pin<Output<PushPull>>, config: SpiConfig,
+        tx_buffor: u32, rx_buffor: u32, buffor_len: usize) -> Self
+    {
+        cs.set_high().ok();
+        SpiDevice { cs, config, tx_buffor, rx_buffor, buffor_len: buffor_len.max(1) }
+    }
+
+    pub fn free(self) -> Pin<Output<PushPull>> {
+        self.cs
+    }
+
+    pub fn config(&self) -> SpiConfig {
+        self.config
+    }
+
+    pub fn set_config(&mut self, config: SpiConfig) {
+        self.config = config;
+    }
+
+    /// Borrow the bus, the result implements embedded-hal SPI traits
+    pub fn on<'a, T: spim::Instance>(&'a mut self, spim: &'a mut Spim<T>) -> SpiBound<'a, T> {
+        SpiBound { device: self, spim }
+    }
+}
+
+
+/// `SpiDevice` together with its bus, chip select is held for one call
+pub struct SpiBound<'a, T: spim::Instance> {
+    device: &'a mut SpiDevice,
+    spim: &'a mut Spim<T>,
+}
+
+impl<T> SpiBound<'_, T>
+where
+    T: spim::Instance,
+{
+    fn select(&mut self) {
+        self.spim.set_mode(self.device.config.mode);
+        self.spim.set_frequency(self.device.config.frequency);
+        self.device.cs.set_low().ok();
+    }
+
+    fn deselect(&mut self) {
+        self.device.cs.set_high().ok();
+    }
+
+    // Data goes in chunks through the DMA region of the device
+    fn write_words(&mut self, words: &[u8]) -> Result<(), SpiError> {
+        let (tx_buffor, rx_buffor, len) = self.buffors();
+        let tx = unsafe { core::slice::from_raw_parts_mut(tx_buffor as *mut u8, len) };
+        for chunk in words.chunks(len) {
+            tx[..chunk.len()].copy_from_slice(chunk);
+            self.spim.transfer(tx_buffor, chunk.len(), rx_buffor, 0)?;
+        }
+        Ok(())
+    }
+
+    fn transfer_words(&mut self, words: &mut [u8]) -> Result<(), SpiError> {
+        let (tx_buffor, rx_buffor, len) = self.buffors();
+        let tx = unsafe { core::slice::from_raw_parts_mut(tx_buffor as *mut u8, len) };
+        let rx = unsafe { core::slice::from_raw_parts(rx_buffor as *const u8, len) };
+        for chunk in words.chunks_mut(len) {
+            tx[..chunk.len()].copy_from_slice(chunk);
+            self.spim.transfer(tx_buffor, chunk.len(), rx_buffor, chunk.len())?;
+            chunk.copy_from_slice(&rx[..chunk.len()]);
+        }
+        Ok(())
+    }
+
+    fn buffors(&self) -> (u32, u32, usize) {
+        (self.device.tx_buffor, self.device.rx_buffor, self.device.buffor_len)
+    }
+}
+
+impl<T: spim::Instance> Write<u8> for SpiBound<'_, T> {
+    type Error = SpiError;
+
+    fn write(&mut self, words: &[u8]) -> Result<(), SpiError> {
+        self.select();
+        let result = self.write_words(words);
+        self.deselect();
+        result
+    }
+}
+
+impl<T: spim::Instance> Transfer<u8> for SpiBound<'_, T> {
+    type Error = SpiError;
+
+    fn transfer<'w>(&mut self, words: &'w mut [u8]) -> Result<&'w [u8], SpiError> {
+        self.select();
+        let result = self.transfer_words(words);
+        self.deselect();
+        result.map(|_| &*words)
+    }
+}
+
+impl<T: spim::Instance> Transactional<u8> for SpiBound<'_, T> {
+    type Error = SpiError;
+
+    /// All operations under one chip select, e.g. flash command then data
+    fn exec(&mut self, operations: &mut [Operation<'_, u8>]) -> Result<(), SpiError> {
+        self.select();
+        let result = operations.iter_mut().try_for_each(|operation| match operation {
+            Operation::Write(words) => self.write_words(words),
+            Operation::Transfer(words) => self.transfer_words(words),
+        });
+        self.deselect();
+        result
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ram_blocks_of_buffer() {
+        assert_eq!(ram_blocks(0x2000_0100, 0), 0);
+        assert_eq!(ram_blocks(0x2000_0100, 256), 0b1);
+        assert_eq!(ram_blocks(0x2000_1F00, 512), 0b11);
+        assert_eq!(ram_blocks(0x2000_FF00, 512), 0b1_1000_0000);
+        assert_eq!(ram_blocks(0x2003_0000, 256), 1 << RAM8_BLOCK);
+    }
+
+    #[test]
+    fn timeout_follows_longer_buffer() {
+        assert_eq!(transfer_timeout_us(0, 0), TIMEOUT_MARGIN_US);
+        assert_eq!(transfer_timeout_us(10, 2), 10 * BYTE_US_MAX + TIMEOUT_MARGIN_US);
+        assert_eq!(transfer_timeout_us(2, 10), 10 * BYTE_US_MAX + TIMEOUT_MARGIN_US);
+        // Longest DMA buffer still fits
+        assert!(transfer_timeout_us(u16::MAX as usize, 0) > u16::MAX as u32 * BYTE_US_MAX);
+    }
+}
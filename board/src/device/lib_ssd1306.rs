@@ -0,0 +1,174 @@
+// SSD1306 128x64 OLED over I2C
+//
+// The driver doesn't own the bus, every call borrows it, so the display can
+// live next to other devices on the same TWIM. Frames go out through the
+// I2C DMA region, EasyDMA can't read them from flash.
+
+use embedded_hal::blocking::i2c::Write;
+use crate::device::I2C_DATA_BUF_LEN;
+
+pub const SSD1306_ADDR: u8 = 0x3C;
+pub const DISPLAY_WIDTH: usize = 128;
+pub const DISPLAY_HEIGHT: usize = 64;
+pub const DISPLAY_PAGES: usize = DISPLAY_HEIGHT / 8;
+/// Framebuffer size, page major: byte `page * DISPLAY_WIDTH + x`, bit `y % 8`
+pub const FRAMEBUFFER_LEN: usize = DISPLAY_WIDTH * DISPLAY_PAGES;
+
+// First byte of each transfer, Co = 0 and D/C# selects commands or data
+const CONTROL_COMMAND: u8 = 0x00;
+const CONTROL_DATA: u8 = 0x40;
+
+const CMD_DISPLAY_OFF: u8 = 0xAE;
+const CMD_DISPLAY_ON: u8 = 0xAF;
+const CMD_CONTRAST: u8 = 0x81;
+const CMD_ADDRESSING_MODE: u8 = 0x20;
+const CMD_COLUMN_ADDRESS: u8 = 0x21;
+const CMD_PAGE_ADDRESS: u8 = 0x22;
+const CMD_PAGE_START: u8 = 0xB0;
+const CMD_INVERT: u8 = 0xA6;
+
+// Power up sequence for 128x64 panel with internal charge pump
+const INIT_SEQUENCE: [u8; 25] = [
+    CMD_DISPLAY_OFF,
+    0xD5, 0x80,     // Clock divide ratio / oscillator frequency
+    0xA8, 0x3F,     // Multiplex ratio, 64 lines
+    0xD3, 0x00,     // Display offset
+    0x40,           // Start line 0
+    0x8D, 0x14,     // Charge pump on
+    CMD_ADDRESSING_MODE, 0x00, // Horizontal
+    0xA1,           // Segment remap, column 127 is SEG0
+    0xC8,           // COM scan direction remapped
+    0xDA, 0x12,     // COM pins configuration
+    CMD_CONTRAST, 0xCF,
+    0xD9, 0xF1,     // Pre-charge period
+    0xDB, 0x40,     // VCOMH deselect level
+    0xA4,           // Show RAM content
+    CMD_INVERT,     // Normal, not inverted
+    0x2E,           // Scrolling off
+];
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum AddressingMode {
+    Horizontal = 0x00,
+    Vertical = 0x01,
+    Page = 0x02,
+}
+
+
+pub struct Ssd1306 {
+    address: u8,
+    mode: AddressingMode,
+    buffor: u32,
+}
+
+impl Ssd1306 {
+    /// `i2c_buffor` is RAM address of `I2C_DATA_BUF_LEN` bytes (`I2C_DATA_BUF`)
+    pub fn new(address: u8, i2c_buffor: u32) -> Self {
+        Ssd1306 {
+            address,
+            mode: AddressingMode::Horizontal,
+            buffor: i2c_buffor,
+        }
+    }
+
+    pub fn address(&self) -> u8 {
+        self.address
+    }
+
+    pub fn addressing_mode(&self) -> AddressingMode {
+        self.mode
+    }
+
+    /// Configure the panel, clear its RAM and turn it on
+    pub fn init<I: Write>(&mut self, i2c: &mut I) -> Result<(), I::Error> {
+        self.command(i2c, &INIT_SEQUENCE)?;
+        self.mode = AddressingMode::Horizontal;
+        self.clear(i2c)?;
+        self.set_display_on(i2c, true)
+    }
+
+    pub fn set_display_on<I: Write>(&mut self, i2c: &mut I, on: bool) -> Result<(), I::Error> {
+        self.command(i2c, &[if on { CMD_DISPLAY_ON } else { CMD_DISPLAY_OFF }])
+    }
+
+    pub fn set_contrast<I: Write>(&mut self, i2c: &mut I, contrast: u8) -> Result<(), I::Error> {
+        self.command(i2c, &[CMD_CONTRAST, contrast])
+    }
+
+    pub fn set_inverted<I: Write>(&mut self, i2c: &mut I, inverted: bool) -> Result<(), I::Error> {
+        self.command(i2c, &[CMD_INVERT | inverted as u8])
+    }
+
+    pub fn set_addressing_mode<I: Write>(&mut self, i2c: &mut I, mode: AddressingMode)
+        -> Result<(), I::Error>
+    {
+        self.command(i2c, &[CMD_ADDRESSING_MODE, mode as u8])?;
+        self.mode = mode;
+        Ok(())
+    }
+
+    /// Blank the display RAM
+    pub fn clear<I: Write>(&mut self, i2c: &mut I) -> Result<(), I::Error> {
+        self.flush_region(i2c, &[0; FRAMEBUFFER_LEN], 0..DISPLAY_PAGES, 0..DISPLAY_WIDTH)
+    }
+
+    /// Send the whole framebuffer
+    pub fn flush<I: Write>(&mut self, i2c: &mut I, frame: &[u8; FRAMEBUFFER_LEN])
+        -> Result<(), I::Error>
+    {
+        self.flush_region(i2c, frame, 0..DISPLAY_PAGES, 0..DISPLAY_WIDTH)
+    }
+
+    /// Send `columns` of `pages` only, the rest of the display keeps its content
+    pub fn flush_region<I: Write>(&mut self, i2c: &mut I, frame: &[u8; FRAMEBUFFER_LEN],
+        pages: core::ops::Range<usize>, columns: core::ops::Range<usize>) -> Result<(), I::Error>
+    {
+        let pages = pages.start..pages.end.min(DISPLAY_PAGES);
+        let columns = columns.start..columns.end.min(DISPLAY_WIDTH);
+        if pages.is_empty() || columns.is_empty() {
+            return Ok(());
+        }
+
+        // Window addressing needs horizontal mode, in page mode each page is addressed alone
+        if self.mode == AddressingMode::Vertical {
+            self.set_addressing_mode(i2c, AddressingMode::Horizontal)?;
+        }
+
+        for page in pages.clone() {
+            if self.mode == AddressingMode::Page {
+                let column = columns.start as u8;
+                self.command(i2c, &[CMD_PAGE_START | page as u8, column & 0x0F, 0x10 | column >> 4])?;
+            } else {
+                self.command(i2c, &[
+                    CMD_COLUMN_ADDRESS, columns.start as u8, columns.end as u8 - 1,
+                    CMD_PAGE_ADDRESS, page as u8, page as u8,
+                ])?;
+            }
+            let row = page * DISPLAY_WIDTH;
+            self.data(i2c, &frame[row + columns.start..row + columns.end])?;
+        }
+        Ok(())
+    }
+
+    fn command<I: Write>(&mut self, i2c: &mut I, commands: &[u8]) -> Result<(), I::Error> {
+        self.send(i2c, CONTROL_COMMAND, commands)
+    }
+
+    fn data<I: Write>(&mut self, i2c: &mut I, data: &[u8]) -> Result<(), I::Error> {
+        self.send(i2c, CONTROL_DATA, data)
+    }
+
+    // Copy into DMA region behind the control byte, longer data goes in chunks
+    fn send<I: Write>(&mut self, i2c: &mut I, control: u8, bytes: &[u8]) -> Result<(), I::Error> {
+        let buffor = unsafe {
+            core::slice::from_raw_parts_mut(self.buffor as *mut u8, I2C_DATA_BUF_LEN as usize)
+        };
+        for chunk in bytes.chunks(buffor.len() - 1) {
+            buffor[0] = control;
+            buffor[1..=chunk.len()].copy_from_slice(chunk);
+            i2c.write(self.address, &buffor[..=chunk.len()])?;
+        }
+        Ok(())
+    }
+}
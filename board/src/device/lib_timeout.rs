@@ -0,0 +1,261 @@
+// Named timeouts on top of the RTIC monotonic
+//
+// Nothing here waits. The application keeps one task spawned after
+// `next_deadline`, which pops expired keys and spawns whatever handles
+// them. Time is u32 milliseconds from the monotonic, the board's other timing
+// logic (gestures, LED patterns) uses `remaining` to compare it as well.
+
+/// Timeouts armed at once by default
+pub const TIMEOUT_SLOTS: usize = 8;
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum TimeoutError {
+    /// Every slot is armed
+    Full,
+}
+
+#[derive(Clone, Copy)]
+struct Timeout<K> {
+    key: K,
+    deadline: u32,
+}
+
+
+/// Up to `N` timeouts, each identified by key `K`
+pub struct Timeouts<K: Copy + PartialEq, const N: usize = TIMEOUT_SLOTS> {
+    slots: [Option<Timeout<K>>; N],
+}
+
+impl<K, const N: usize> Timeouts<K, N>
+where
+    K: Copy + PartialEq,
+{
+    pub fn new() -> Self {
+        Timeouts { slots: [None; N] }
+    }
+
+    /// Expire `key` after `ms`, an armed one is moved to the new deadline
+    pub fn arm(&mut self, key: K, now: u32, ms: u32) -> Result<(), TimeoutError> {
+        let timeout = Timeout { key, deadline: now.wrapping_add(ms) };
+        let slot = match self.position(key) {
+            Some(index) => &mut self.slots[index],
+            None => self.slots.iter_mut().find(|slot| slot.is_none()).ok_or(TimeoutError::Full)?,
+        };
+        *slot = Some(timeout);
+        Ok(())
+    }
+
+    /// Returns `true` if `key` was armed
+    pub fn cancel(&mut self, key: K) -> bool {
+        match self.position(key) {
+            Some(index) => {
+                self.slots[index] = None;
+                true
+            },
+            None => false,
+        }
+    }
+
+    pub fn is_armed(&self, key: K) -> bool {
+        self.position(key).is_some()
+    }
+
+    /// Milliseconds until `key` expires, 0 once it's due
+    pub fn remaining(&self, key: K, now: u32) -> Option<u32> {
+        self.position(key)
+            .and_then(|index| self.slots[index])
+            .map(|timeout| remaining(timeout.deadline, now))
+    }
+
+    /// Disarm and return one expired key, call until `None`
+    pub fn pop_expired(&mut self, now: u32) -> Option<K> {
+        let slot = self.slots.iter_mut()
+            .find(|slot| matches!(slot, Some(timeout) if remaining(timeout.deadline, now) == 0))?;
+        slot.take().map(|timeout| timeout.key)
+    }
+
+    /// Milliseconds until the earliest timeout, spawn the expiry task after it
+    pub fn next_deadline(&self, now: u32) -> Option<u32> {
+        self.slots.iter()
+            .flatten()
+            .map(|timeout| remaining(timeout.deadline, now))
+            .min()
+    }
+
+    fn position(&self, key: K) -> Option<usize> {
+        self.slots.iter().position(|slot| matches!(slot, Some(timeout) if timeout.key == key))
+    }
+}
+
+impl<K, const N: usize> Default for Timeouts<K, N>
+where
+    K: Copy + PartialEq,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Deadline of the one timed run of a task, e.g. gesture timeout or LED step
+///
+/// Each timed spawn carries the deadline it was planned for. Replanning
+/// doesn't cancel the old spawn: it runs later, `fired` tells it's stale and
+/// the task does nothing, so no spawn handle has to be kept.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TickPlan {
+    deadline: Option<u32>,
+}
+
+impl TickPlan {
+    pub fn new() -> Self {
+        TickPlan { deadline: None }
+    }
+
+    /// Timed run spawned for `deadline` started, `false` if it was replanned since
+    pub fn fired(&mut self, deadline: u32) -> bool {
+        if self.deadline != Some(deadline) {
+            return false;
+        }
+        self.deadline = None;
+        true
+    }
+
+    /// Next run wanted `left` ms from `now`, `None` for no run; `schedule(left, deadline)`
+    /// spawns the task only when the deadline moved and returns `false` if that failed
+    pub fn plan(&mut self, now: u32, left: Option<u32>, schedule: impl FnOnce(u32, u32) -> bool) {
+        let Some(left) = left else {
+            self.deadline = None;
+            return;
+        };
+        let deadline = now.wrapping_add(left);
+        if self.deadline != Some(deadline) {
+            self.deadline = if schedule(left, deadline) { Some(deadline) } else { None };
+        }
+    }
+}
+
+
+/// Milliseconds from `now` to `deadline`, 0 once it has passed; stays right
+/// across the u32 wraparound for deadlines less than ~24 days away
+pub(crate) fn remaining(deadline: u32, now: u32) -> u32 {
+    let left = deadline.wrapping_sub(now);
+    // Expired deadlines wrap around to huge values
+    if left > u32::MAX / 2 { 0 } else { left }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    enum Key {
+        A,
+        B,
+        C,
+    }
+
+    #[test]
+    fn remaining_counts_down_to_zero() {
+        assert_eq!(remaining(100, 40), 60);
+        assert_eq!(remaining(100, 100), 0);
+        assert_eq!(remaining(100, 101), 0);
+        assert_eq!(remaining(5, u32::MAX - 4), 10);
+        assert_eq!(remaining(u32::MAX - 4, 5), 0);
+    }
+
+    #[test]
+    fn expires_across_wraparound() {
+        let mut timeouts: Timeouts<Key> = Timeouts::new();
+        let now = u32::MAX - 10;
+        timeouts.arm(Key::A, now, 30).unwrap();
+
+        assert_eq!(timeouts.remaining(Key::A, now), Some(30));
+        assert_eq!(timeouts.next_deadline(u32::MAX), Some(20));
+        assert_eq!(timeouts.pop_expired(5), None);
+        assert_eq!(timeouts.remaining(Key::A, 19), Some(0));
+        assert_eq!(timeouts.pop_expired(19), Some(Key::A));
+        assert!(!timeouts.is_armed(Key::A));
+    }
+
+    #[test]
+    fn rearm_moves_deadline() {
+        let mut timeouts: Timeouts<Key> = Timeouts::new();
+        timeouts.arm(Key::A, 0, 100).unwrap();
+        timeouts.arm(Key::A, 50, 100).unwrap();
+
+        assert_eq!(timeouts.pop_expired(100), None);
+        assert_eq!(timeouts.next_deadline(100), Some(50));
+        assert_eq!(timeouts.pop_expired(150), Some(Key::A));
+    }
+
+    #[test]
+    fn cancel_disarms() {
+        let mut timeouts: Timeouts<Key> = Timeouts::new();
+        timeouts.arm(Key::A, 0, 10).unwrap();
+
+        assert!(timeouts.cancel(Key::A));
+        assert!(!timeouts.cancel(Key::A));
+        assert_eq!(timeouts.remaining(Key::A, 0), None);
+        assert_eq!(timeouts.pop_expired(20), None);
+        assert_eq!(timeouts.next_deadline(20), None);
+    }
+
+    #[test]
+    fn full_table_refuses_new_keys() {
+        let mut timeouts: Timeouts<Key, 2> = Timeouts::new();
+        timeouts.arm(Key::A, 0, 10).unwrap();
+        timeouts.arm(Key::B, 0, 20).unwrap();
+
+        assert_eq!(timeouts.arm(Key::C, 0, 30), Err(TimeoutError::Full));
+        // Armed keys can still move
+        assert_eq!(timeouts.arm(Key::B, 0, 5), Ok(()));
+        assert_eq!(timeouts.next_deadline(0), Some(5));
+
+        assert_eq!(timeouts.pop_expired(5), Some(Key::B));
+        assert_eq!(timeouts.arm(Key::C, 5, 30), Ok(()));
+    }
+
+    #[test]
+    fn tick_plan_ignores_stale_runs() {
+        let mut plan = TickPlan::new();
+        let mut spawned = None;
+        plan.plan(0, Some(100), |left, deadline| { spawned = Some((left, deadline)); true });
+        assert_eq!(spawned, Some((100, 100)));
+        plan.plan(10, Some(90), |_, _| panic!("deadline didn't move"));
+
+        // Moved earlier, the run for 100 is stale now
+        plan.plan(20, Some(30), |left, deadline| { spawned = Some((left, deadline)); true });
+        assert_eq!(spawned, Some((30, 50)));
+        assert!(plan.fired(50));
+        assert!(!plan.fired(100));
+        assert!(!plan.fired(50));
+    }
+
+    #[test]
+    fn tick_plan_forgets_failed_and_unwanted_runs() {
+        let mut plan = TickPlan::new();
+        plan.plan(0, Some(10), |_, _| false);
+        assert!(!plan.fired(10));
+        plan.plan(0, Some(10), |_, _| true);
+        plan.plan(5, None, |_, _| panic!("nothing wanted"));
+        assert!(!plan.fired(10));
+    }
+
+    #[test]
+    fn pops_every_expired_key() {
+        let mut timeouts: Timeouts<Key> = Timeouts::new();
+        timeouts.arm(Key::A, 0, 10).unwrap();
+        timeouts.arm(Key::B, 0, 20).unwrap();
+        timeouts.arm(Key::C, 0, 30).unwrap();
+
+        let mut expired = [None; 3];
+        for slot in expired.iter_mut() {
+            *slot = timeouts.pop_expired(25);
+        }
+        assert!(expired[..2].contains(&Some(Key::A)) && expired[..2].contains(&Some(Key::B)));
+        assert_eq!(expired[2], None);
+        assert_eq!(timeouts.next_deadline(25), Some(5));
+    }
+}
@@ -0,0 +1,247 @@
+// TIMER1..=3 as general purpose hardware timers
+//
+// TIMER0 stays with `hal::Timer` for `Uarte::receive`. Timer mode ticks at
+// 1 MHz, so times are in microseconds. Compare channel 0 drives one-shot and
+// periodic modes, the last channel (3, or 5 on TIMER3) is taken by `now`;
+// channels in between are free for the application.
+//
+// RTIC: bind a hardware task to `HwTimer::<TIMERx>::INTERRUPT` (TIMER1..3)
+// and call `on_interrupt` from it.
+
+use crate::hal_main as hal;
+pub use hal::pac::{TIMER1, TIMER2, TIMER3};
+pub use hal::timer::Instance as TimerInstance;
+
+use hal::pac::{Interrupt, NVIC};
+
+/// Compare/capture channels of TIMER0..=2
+pub const TIMER_CHANNELS: usize = 4;
+/// Compare/capture channels of TIMER3 and TIMER4
+pub const TIMER3_CHANNELS: usize = 6;
+// 16 MHz / 2^4
+const PRESCALER_1MHZ: u8 = 4;
+// PAC register block stops at 4 channels, TIMER3 goes on at the same stride
+const TASKS_CAPTURE: usize = 0x040;
+const EVENTS_COMPARE: usize = 0x140;
+const CC: usize = 0x540;
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum TimerError {
+    /// Channel number past the channels of this timer
+    InvalidChannel,
+    /// Channel is captured by `now`
+    ReservedChannel,
+}
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum TimerMode {
+    /// Compare 0 fires once after `us`, timer stops itself
+    OneShot { us: u32 },
+    /// Compare 0 fires every `us`
+    Periodic { us: u32 },
+    /// Counts COUNT tasks (PPI) instead of clock ticks
+    Counter,
+    /// Free running 1 MHz, use `set_compare` and `capture`
+    FreeRunning,
+}
+
+
+/// Compare channels that fired, bit 0 is channel 0
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub struct TimerEvents(pub u8);
+
+impl TimerEvents {
+    pub fn fired(&self, channel: usize) -> bool {
+        self.0 & 1 << channel != 0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+}
+
+
+pub struct HwTimer<T: TimerInstance> {
+    timer: T,
+    mode: Option<TimerMode>,
+}
+
+impl<T> HwTimer<T>
+where
+    T: TimerInstance,
+{
+    /// Interrupt to bind the RTIC task to
+    pub const INTERRUPT: Interrupt = T::INTERRUPT;
+    /// Compare/capture channels of this instance
+    pub const CHANNELS: usize = if matches!(T::INTERRUPT, Interrupt::TIMER3) { TIMER3_CHANNELS } else { TIMER_CHANNELS };
+    /// Channel `now` captures into
+    pub const NOW_CHANNEL: usize = Self::CHANNELS - 1;
+
+    /// Stopped timer, 32-bit, interrupts off
+    pub fn new(timer: T) -> Self {
+        let regs = timer.as_timer0();
+        regs.tasks_stop.write(|w| unsafe { w.bits(1) });
+        regs.bitmode.write(|w| w.bitmode()._32bit());
+        regs.intenclr.write(|w| unsafe { w.bits(0xFFFF_FFFF) });
+        regs.shorts.reset();
+        HwTimer { timer, mode: None }
+    }
+
+    pub fn free(mut self) -> T {
+        self.stop();
+        self.timer
+    }
+
+    /// Mode of the last `start`, `None` before the first one
+    pub fn mode(&self) -> Option<TimerMode> {
+        self.mode
+    }
+
+    /// Restart from 0 in `mode`, compare 0 interrupt is enabled for one-shot and periodic
+    /// and disabled otherwise
+    pub fn start(&mut self, mode: TimerMode) {
+        self.stop();
+        for channel in 0..Self::CHANNELS {
+            self.write_channel(EVENTS_COMPARE, channel, 0);
+        }
+        let regs = self.timer.as_timer0();
+
+        match mode {
+            TimerMode::Counter => regs.mode.write(|w| w.mode().low_power_counter()),
+            _ => regs.mode.write(|w| w.mode().timer()),
+        }
+        regs.prescaler.write(|w| unsafe { w.prescaler().bits(PRESCALER_1MHZ) });
+
+        match mode {
+            TimerMode::OneShot { us } => {
+                regs.cc[0].write(|w| unsafe { w.cc().bits(us) });
+                regs.shorts.write(|w| w.compare0_clear().enabled().compare0_stop().enabled());
+                regs.intenset.write(|w| w.compare0().set());
+            },
+            TimerMode::Periodic { us } => {
+                regs.cc[0].write(|w| unsafe { w.cc().bits(us) });
+                regs.shorts.write(|w| w.compare0_clear().enabled());
+                regs.intenset.write(|w| w.compare0().set());
+            },
+            TimerMode::Counter | TimerMode::FreeRunning => {
+                regs.shorts.reset();
+                regs.intenclr.write(|w| w.compare0().clear());
+            },
+        }
+
+        self.mode = Some(mode);
+        regs.tasks_clear.write(|w| unsafe { w.bits(1) });
+        regs.tasks_start.write(|w| unsafe { w.bits(1) });
+    }
+
+    pub fn stop(&mut self) {
+        self.timer.as_timer0().tasks_stop.write(|w| unsafe { w.bits(1) });
+    }
+
+    /// Counter back to 0, timer keeps running
+    pub fn clear(&mut self) {
+        self.timer.as_timer0().tasks_clear.write(|w| unsafe { w.bits(1) });
+    }
+
+    /// Add one in counter mode, normally done by PPI
+    pub fn count(&mut self) {
+        self.timer.as_timer0().tasks_count.write(|w| unsafe { w.bits(1) });
+    }
+
+    /// Copy counter to channel and return it
+    pub fn capture(&mut self, channel: usize) -> Result<u32, TimerError> {
+        self.check(channel)?;
+        self.write_channel(TASKS_CAPTURE, channel, 1);
+        Ok(self.read_channel(CC, channel))
+    }
+
+    /// Last value captured on channel, e.g. by PPI
+    pub fn captured(&self, channel: usize) -> Result<u32, TimerError> {
+        self.check(channel)?;
+        Ok(self.read_channel(CC, channel))
+    }
+
+    /// Time since start in microseconds, counts in counter mode;
+    /// overwrites `NOW_CHANNEL`
+    pub fn now(&mut self) -> u32 {
+        self.write_channel(TASKS_CAPTURE, Self::NOW_CHANNEL, 1);
+        self.read_channel(CC, Self::NOW_CHANNEL)
+    }
+
+    /// Fire compare event on channel when counter reaches `value`
+    pub fn set_compare(&mut self, channel: usize, value: u32, interrupt: bool) -> Result<(), TimerError> {
+        self.check(channel)?;
+        if channel == Self::NOW_CHANNEL {
+            return Err(TimerError::ReservedChannel);
+        }
+        self.write_channel(EVENTS_COMPARE, channel, 0);
+        self.write_channel(CC, channel, value);
+        if interrupt {
+            self.enable_interrupt(channel)
+        } else {
+            self.disable_interrupt(channel)
+        }
+    }
+
+    pub fn enable_interrupt(&mut self, channel: usize) -> Result<(), TimerError> {
+        self.check(channel)?;
+        self.timer.as_timer0().intenset.write(|w| unsafe { w.bits(1 << (16 + channel)) });
+        Ok(())
+    }
+
+    pub fn disable_interrupt(&mut self, channel: usize) -> Result<(), TimerError> {
+        self.check(channel)?;
+        self.timer.as_timer0().intenclr.write(|w| unsafe { w.bits(1 << (16 + channel)) });
+        Ok(())
+    }
+
+    pub fn is_compare(&self, channel: usize) -> Result<bool, TimerError> {
+        self.check(channel)?;
+        Ok(self.read_channel(EVENTS_COMPARE, channel) != 0)
+    }
+
+    /// Call from the timer interrupt, clears and returns fired compare events
+    pub fn on_interrupt(&mut self) -> TimerEvents {
+        let mut fired = 0;
+        for channel in 0..Self::CHANNELS {
+            if self.read_channel(EVENTS_COMPARE, channel) != 0 {
+                self.write_channel(EVENTS_COMPARE, channel, 0);
+                fired |= 1 << channel;
+            }
+        }
+        TimerEvents(fired)
+    }
+
+    /// Run the bound task without waiting for the timer
+    pub fn pend(&self) {
+        NVIC::pend(T::INTERRUPT);
+    }
+
+    pub fn unpend(&self) {
+        NVIC::unpend(T::INTERRUPT);
+    }
+
+    /// Registers, for PPI endpoints
+    pub fn registers(&self) -> &hal::pac::timer0::RegisterBlock {
+        self.timer.as_timer0()
+    }
+
+    fn check(&self, channel: usize) -> Result<(), TimerError> {
+        if channel < Self::CHANNELS { Ok(()) } else { Err(TimerError::InvalidChannel) }
+    }
+
+    // Channel register at `offset` from the block, valid for every channel of the instance
+    fn channel_reg(&self, offset: usize, channel: usize) -> *mut u32 {
+        (self.timer.as_timer0() as *const _ as usize + offset + 4 * channel) as *mut u32
+    }
+
+    fn read_channel(&self, offset: usize, channel: usize) -> u32 {
+        unsafe { core::ptr::read_volatile(self.channel_reg(offset, channel)) }
+    }
+
+    fn write_channel(&self, offset: usize, channel: usize, value: u32) {
+        unsafe { core::ptr::write_volatile(self.channel_reg(offset, channel), value) }
+    }
+}
@@ -1,4 +1,5 @@
 use crate::hal_main as hal;
+use crate::device::LowPower;
 
 use embedded_hal::prelude::_embedded_hal_timer_CountDown;
 pub use hal::Timer;
@@ -22,7 +23,7 @@ where
 
     pub fn clear_cts_event(&mut self)   {
         self.0.events_cts.reset();
-        while self.0.events_cts.read().events_cts().bit_is_set() == true  {}
+        while self.0.events_cts.read().events_cts().bit_is_set()  {}
     }
 
     pub fn is_ncts(&mut self) -> bool    {
@@ -31,9 +32,10 @@ where
         self.0.events_endrx.read().events_endrx().bit_is_set()
     }
 
-    /// Write via UARTE.
+    /// Receive up to `rx_len` bytes, gives up when `timer` runs out
     ///
-    /// This method uses transmits all bytes in `tx_buffer`with timeout control
+    /// On timeout the receiver is stopped and its FIFO flushed, so a shorter
+    /// message is in `rx_buffor` too, `received` tells its length
     pub fn receive<I>(&mut self,
         rx_buffor: u32, rx_len: u8,
         timer: &mut Timer<I>) 
@@ -66,6 +68,11 @@ where
         //while self.0.events_endrx.read().bits() == 0 {}
 
 
+        // Shorter message, push what came so far to the buffer
+        if !_event_completed {
+            self.cancel_receive();
+        }
+
         // Reset everything and be ready for next message
         self.finalize_receive();
 
@@ -77,6 +84,31 @@ where
 
         Ok(())
     }
+    /// Start receiving without waiting, ENDRX interrupt fires once the buffer
+    /// is full. Finish with `end_receive`, also when your timeout expires.
+    pub fn begin_receive(&mut self, rx_buffor: u32, rx_len: u8) -> Result<(), Error> {
+        self.0.events_endrx.reset();
+        self.start_receive(rx_buffor, rx_len)?;
+        self.0.intenset.write(|w| w.endrx().set());
+        Ok(())
+    }
+
+    /// Stop reception started by `begin_receive`, returns bytes received
+    pub fn end_receive(&mut self) -> usize {
+        self.0.intenclr.write(|w| w.endrx().clear());
+        // Shorter message, push what came so far to the buffer
+        if !self.is_rx_done() {
+            self.cancel_receive();
+        }
+        self.finalize_receive();
+        self.received()
+    }
+
+    /// Buffer of the running reception is full
+    pub fn is_rx_done(&self) -> bool {
+        self.0.events_endrx.read().events_endrx().bit_is_set()
+    }
+
     /// Start a UARTE read transaction by setting the control
     /// values and triggering a read task.
     fn start_receive(&mut self, rx_buffor: u32, rx_len: u8) -> Result<(), Error> {
@@ -84,7 +116,7 @@ where
             return Err(Error::RxBufferTooSmall);
         }
     
-        if *&rx_len as usize > EASY_DMA_SIZE {
+        if rx_len as usize > EASY_DMA_SIZE {
             return Err(Error::RxBufferTooLong);
         }
 
@@ -118,7 +150,7 @@ where
 
 
     /// Stop an unfinished UART read transaction and flush FIFO to DMA buffer.
-    fn _cancel_receive(&mut self) {
+    fn cancel_receive(&mut self) {
         // Stop reception.
         self.0.tasks_stoprx.write(|w| unsafe { w.bits(1) });
 
@@ -141,12 +173,43 @@ where
 
 
 
+    /// Registers, for PPI endpoints
+    pub fn registers(&self) -> &uarte0::RegisterBlock {
+        &self.0
+    }
+
+    /// Bytes stored in the rx buffer by the last receive
+    pub fn received(&self) -> usize {
+        self.0.rxd.amount.read().bits() as usize
+    }
+
+    /// Transmit any number of bytes, copied in chunks through `tx_buffor` of `tx_len` bytes
+    pub fn write_bytes(&mut self, tx_buffor: u32, tx_len: u16, bytes: &[u8]) -> Result<(), Error> {
+        if tx_len == 0 {
+            return Err(Error::TxBufferTooSmall);
+        }
+
+        let buffor = unsafe {
+            core::slice::from_raw_parts_mut(tx_buffor as *mut u8, tx_len as usize)
+        };
+        for chunk in bytes.chunks(buffor.len()) {
+            buffor[..chunk.len()].copy_from_slice(chunk);
+            self.transmit(tx_buffor, chunk.len() as u16)?;
+        }
+        Ok(())
+    }
+
+    /// `core::fmt::Write` on top of `write_bytes`
+    pub fn writer(&mut self, tx_buffor: u32, tx_len: u16) -> UarteWriter<'_, T> {
+        UarteWriter { uarte: self, buffor: tx_buffor, len: tx_len }
+    }
+
     pub fn transmit(&mut self, tx_buffor: u32, tx_len: u16) ->  Result<(), Error>  {
         if tx_len == 0 {
             return Err(Error::TxBufferTooSmall);
         }
 
-        if *&tx_len as usize > EASY_DMA_SIZE {
+        if tx_len as usize > EASY_DMA_SIZE {
             return Err(Error::TxBufferTooLong);
         }
 
@@ -274,6 +337,41 @@ where
 
 }
 
+impl<T> LowPower for Uarte<T>
+where
+    T: Instance,
+{
+    /// Enabled receiver keeps HFCLK running even with nothing to receive
+    fn prepare_for_sleep(&mut self) {
+        self.0.tasks_stoptx.write(|w| unsafe { w.bits(1) });
+        self.0.tasks_stoprx.write(|w| unsafe { w.bits(1) });
+        // RXTO comes only from a running receiver, give it 1 ms
+        cortex_m::asm::delay(64_000);
+        self.0.events_rxto.reset();
+        self.0.enable.write(|w| w.enable().disabled());
+    }
+
+    fn resume(&mut self) {
+        self.0.enable.write(|w| w.enable().enabled());
+    }
+}
+
+
+pub struct UarteWriter<'a, T> {
+    uarte: &'a mut Uarte<T>,
+    buffor: u32,
+    len: u16,
+}
+
+impl<T> core::fmt::Write for UarteWriter<'_, T>
+where
+    T: Instance,
+{
+    fn write_str(&mut self, text: &str) -> core::fmt::Result {
+        self.uarte.write_bytes(self.buffor, self.len, text.as_bytes())
+            .map_err(|_| core::fmt::Error)
+    }
+}
 
 
 #[derive(Debug)]
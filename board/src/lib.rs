@@ -1,6 +1,7 @@
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 use nrf52840_hal as hal_main;
-pub use defmt_rtt as _;
+// Links the defmt RTT transport into every binary
+extern crate defmt_rtt;
 
 pub mod device;
 pub use device::*;
@@ -9,7 +9,6 @@ use defmt_rtt as _;
 #[app(device = board, peripherals = false, dispatchers = [SWI0_EGU0,
                                                         SWI1_EGU1])] 
 mod app {
-    use board::*;
 
     #[local]
     struct LocalResources {
@@ -23,7 +22,7 @@ mod app {
     #[init]
     fn init(_ctx: init::Context) 
     -> (SharedResources, LocalResources, init::Monotonics) {
-        let my_board = board::init_board().unwrap();
+        let _my_board = board::BoardBuilder::bare().build().unwrap();
         defmt::info!("Board initialized\n----------");
 
 
@@ -39,7 +38,7 @@ mod app {
     }
 
     #[task()]
-    fn example(cx: example::Context)  {
+    fn example(_cx: example::Context)  {
     }
 
 }
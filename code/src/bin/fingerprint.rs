@@ -0,0 +1,331 @@
+#![no_std]
+#![no_main]
+
+use rtic::app;
+use panic_probe as _;
+use defmt_rtt as _;
+
+
+#[app(device = board, peripherals = false, dispatchers = [SWI0_EGU0,
+                                                        SWI1_EGU1])]
+mod app {
+    use board::*;
+    use systick_monotonic::*;
+    use core::fmt::Write;
+
+    #[monotonic(binds = SysTick, default = true)]
+    type MyMono = Systick<1000>;
+
+    // What GPIOTE registrations stand for
+    #[derive(Clone, Copy, PartialEq, Eq)]
+    pub enum GpioEvent {
+        Buttons,
+        Fingerprint,
+    }
+
+    // Sensor work requested by the buttons
+    #[derive(Clone, Copy, PartialEq, Eq)]
+    pub enum SensorCommand {
+        Enroll,
+        Identify,
+        ListTemplates,
+        WaitFinger,
+        DeleteAll,
+        Cancel,
+    }
+
+    #[local]
+    struct LocalResources {
+        buttons: Buttons,
+    }
+
+    #[shared]
+    struct SharedResources {
+        #[lock_free]
+        leds: Leds,
+        #[lock_free]
+        gpiote: GpioteManager<GpioEvent>,
+        #[lock_free]
+        button_pipeline: ButtonPipeline,
+        #[lock_free]
+        led_patterns: LedPatterns,
+        // Profiles without BM-Lite leave it out
+        #[lock_free]
+        sensor: Option<BmLite<SPIM2>>,
+        #[lock_free]
+        flow: FingerprintFlow,
+        // `None` when the last id is taken or stored ids couldn't be read
+        #[lock_free]
+        next_id: Option<u16>,
+        #[lock_free]
+        uarte: Uarte<UARTE0>,
+        #[lock_free]
+        i2c: I2c<TWIM0>,
+        #[lock_free]
+        display: Ssd1306,
+        #[lock_free]
+        frame: Framebuffer,
+    }
+
+    #[init]
+    fn init(_ctx: init::Context)
+    -> (SharedResources, LocalResources, init::Monotonics) {
+        let my_board = BoardBuilder::new()
+            .cts(None)
+            .twis(None)
+            .spi(None, None)
+            .nfct(false)
+            .build().unwrap();
+        defmt::info!("Board initialized\n----------");
+
+        let mono = Systick::new(_ctx.core.SYST, 64_000_000);
+
+        let mut sensor = match (my_board.board_spim, my_board.board_bmlite_pins) {
+            (Some(spim), Some(pins)) => Some(BmLite::new(spim, pins, SPI_TX_BUF_DEF, SPI_RX_BUF_DEF)),
+            _ => {
+                defmt::error!("No BM-Lite on this board, sensor disabled");
+                None
+            },
+        };
+        if let Some(sensor) = sensor.as_mut() {
+            sensor.reset();
+        }
+
+        // Buttons share PORT event, sensor IRQ rising edge gets own channel
+        let buttons = my_board.buttons;
+        let mut gpiote = GpioteManager::new(my_board.board_gpiote);
+        for button in [&buttons._1, &buttons._2, &buttons._3, &buttons._4] {
+            gpiote.port(&button.inner, PortEventSense::Low, GpioEvent::Buttons).unwrap();
+        }
+        if let Some(sensor) = sensor.as_ref() {
+            gpiote.channel(sensor.irq_pin(), EventPolarity::LoToHi, GpioEvent::Fingerprint).unwrap();
+        }
+
+        let mut i2c = my_board.board_i2c.unwrap();
+        let mut display = Ssd1306::new(SSD1306_ADDR, I2C_DATA_BUF);
+        if display.init(&mut i2c).is_err() {
+            defmt::error!("SSD1306 not responding");
+        }
+
+        // New templates go after the highest stored id, gaps below it aren't reused
+        let stored = sensor.as_mut().map(|sensor| sensor.template_ids(&mut []));
+        let next_id = match sensor.as_mut().map(|sensor| sensor.highest_template_id()) {
+            Some(Ok(Some(highest))) => highest.checked_add(1),
+            Some(Ok(None)) | None => Some(0),
+            Some(Err(error)) => {
+                // Unknown ids, enrolling could overwrite one
+                defmt::error!("Template ids unknown: {}", error);
+                None
+            },
+        };
+
+        defmt::info!("Peripherials turned on\n----------");
+        if let Some(stored) = stored {
+            report::spawn(stored).ok();
+        }
+
+        (
+            SharedResources {
+                leds: my_board.leds,
+                gpiote,
+                button_pipeline: ButtonPipeline::new(20, GestureConfig::default()),
+                led_patterns: LedPatterns::new(),
+                sensor,
+                flow: FingerprintFlow::new(),
+                next_id,
+                uarte: my_board.board_uarte.unwrap(),
+                i2c,
+                display,
+                frame: Framebuffer::new(),
+            },
+            LocalResources  {
+                buttons,
+            },
+            init::Monotonics(mono),
+        )
+    }
+
+    // Button handling runs at priority 2, above the blocking sensor transfers
+    #[task(binds = GPIOTE, priority = 2, shared = [gpiote, button_pipeline])]
+    fn GPIOTE_interrupt(cx: GPIOTE_interrupt::Context)  {
+        for event in cx.shared.gpiote.dispatch() {
+            match event {
+                GpioEvent::Buttons => cx.shared.button_pipeline.edge(spawn_debounce),
+                GpioEvent::Fingerprint => {
+                    fingerprint_irq::spawn().ok();
+                },
+            }
+        }
+    }
+
+    #[task(priority = 2, local = [buttons], shared = [button_pipeline, gpiote])]
+    fn debounce(cx: debounce::Context)  {
+        let buttons = cx.local.buttons;
+        if buttons.debounce(cx.shared.button_pipeline, cx.shared.gpiote, now_ms(), spawn_debounce) {
+            button_events::spawn().ok();
+        }
+    }
+
+    #[task(priority = 2, capacity = 4, shared = [button_pipeline])]
+    fn gesture_tick(cx: gesture_tick::Context, deadline: u32)  {
+        if cx.shared.button_pipeline.poll(now_ms(), deadline) {
+            button_events::spawn().ok();
+        }
+    }
+
+    fn spawn_debounce(settle_ms: u32) -> bool {
+        debounce::spawn_after((settle_ms as u64).millis()).is_ok()
+    }
+
+    fn spawn_gesture_tick(left: u32, deadline: u32) -> bool {
+        gesture_tick::spawn_after((left as u64).millis(), deadline).is_ok()
+    }
+
+    // 1 enroll, 2 identify, 3 list templates, 4 wait for finger,
+    // long press 3 deletes all, long press 4 cancels
+    #[task(priority = 2, shared = [button_pipeline])]
+    fn button_events(cx: button_events::Context)  {
+        let pipeline = cx.shared.button_pipeline;
+
+        while let Some(event) = pipeline.pop() {
+            let command = match event {
+                ButtonEvent::Click(1) => SensorCommand::Enroll,
+                ButtonEvent::Click(2) => SensorCommand::Identify,
+                ButtonEvent::Click(3) => SensorCommand::ListTemplates,
+                ButtonEvent::Click(4) => SensorCommand::WaitFinger,
+                ButtonEvent::LongPress(3, _) => SensorCommand::DeleteAll,
+                ButtonEvent::LongPress(4, _) => SensorCommand::Cancel,
+                _ => continue,
+            };
+            sensor_command::spawn(command).ok();
+        }
+
+        pipeline.plan_tick(now_ms(), spawn_gesture_tick);
+    }
+
+    // Sensor transfers block until it answers (up to the IRQ timeout), buttons
+    // preempt them from priority 2; commands wait while a capture is running
+    #[task(capacity = 4, shared = [sensor, flow, next_id])]
+    fn sensor_command(cx: sensor_command::Context, command: SensorCommand)  {
+        let Some(sensor) = cx.shared.sensor.as_mut() else {
+            return;
+        };
+        let flow = cx.shared.flow;
+
+        let started = match command {
+            SensorCommand::Enroll => match *cx.shared.next_id {
+                Some(id) => flow.enroll(sensor, id),
+                None => {
+                    report::spawn(FingerprintEvent::StorageFull).ok();
+                    Ok(())
+                },
+            },
+            SensorCommand::Identify => flow.identify(sensor),
+            SensorCommand::ListTemplates if flow.is_idle() => {
+                report::spawn(sensor.template_ids(&mut [])).ok();
+                Ok(())
+            },
+            SensorCommand::WaitFinger => flow.wait_finger(sensor),
+            SensorCommand::DeleteAll if flow.is_idle() => {
+                let deleted = sensor.delete_template(None);
+                if deleted == FingerprintEvent::Deleted(None) {
+                    *cx.shared.next_id = Some(0);
+                }
+                report::spawn(deleted).ok();
+                Ok(())
+            },
+            SensorCommand::Cancel => {
+                flow.cancel(sensor);
+                Ok(())
+            },
+            SensorCommand::ListTemplates | SensorCommand::DeleteAll => Err(BmLiteError::Busy),
+        };
+        if let Err(error) = started {
+            report::spawn(FingerprintEvent::Failed(error)).ok();
+        }
+    }
+
+    // Sensor has an answer for the running workflow
+    #[task(shared = [sensor, flow, next_id])]
+    fn fingerprint_irq(cx: fingerprint_irq::Context)  {
+        let Some(sensor) = cx.shared.sensor.as_mut() else {
+            return;
+        };
+        if let Some(event) = cx.shared.flow.on_irq(sensor) {
+            if let FingerprintEvent::Enrolled(id) = event {
+                *cx.shared.next_id = id.checked_add(1);
+            }
+            report::spawn(event).ok();
+        }
+    }
+
+    // Show result on LEDs, OLED and UART
+    #[task(capacity = 4, shared = [led_patterns, uarte, i2c, display, frame])]
+    fn report(cx: report::Context, event: FingerprintEvent)  {
+        defmt::info!("fingerprint: {}", event);
+
+        let pattern = match event {
+            FingerprintEvent::Enrolled(_) | FingerprintEvent::Identified(_) =>
+                Pattern::Blink { times: 2, on_ms: 300, off_ms: 200 },
+            FingerprintEvent::NoMatch => Pattern::ErrorCode(1),
+            FingerprintEvent::Failed(_) | FingerprintEvent::StorageFull => Pattern::ErrorCode(3),
+            _ => Pattern::Blink { times: 1, on_ms: 100, off_ms: 0 },
+        };
+        cx.shared.led_patterns.play(1, pattern, now_ms());
+        led_pattern::spawn(None).ok();
+
+        let mut writer = cx.shared.uarte.writer(UARTE_TX_BUF_DEF, UARTE_TX_BUF_MAXLEN);
+        event.write_message(&mut writer).ok();
+        writer.write_str("\r\n").ok();
+
+        let mut line = LineBuffer::new();
+        event.write_message(&mut line).ok();
+        let frame = cx.shared.frame;
+        frame.clear();
+        frame.text(0, 0, "Fingerprint", true);
+        frame.text(0, LINE_HEIGHT * 3, line.as_str(), true);
+        frame.flush(cx.shared.display, cx.shared.i2c).ok();
+    }
+
+    #[task(capacity = 4, shared = [leds, led_patterns])]
+    fn led_pattern(cx: led_pattern::Context, fired: Option<u32>)  {
+        cx.shared.led_patterns.run(now_ms(), cx.shared.leds, fired, spawn_led_pattern);
+    }
+
+    fn spawn_led_pattern(left: u32, deadline: u32) -> bool {
+        led_pattern::spawn_after((left as u64).millis(), Some(deadline)).is_ok()
+    }
+
+    fn now_ms() -> u32 {
+        monotonics::now().duration_since_epoch().to_millis() as u32
+    }
+
+    // One display line of text, longer messages are cut
+    pub struct LineBuffer {
+        bytes: [u8; 21],
+        len: usize,
+    }
+
+    impl LineBuffer {
+        fn new() -> Self {
+            LineBuffer { bytes: [0; 21], len: 0 }
+        }
+
+        fn as_str(&self) -> &str {
+            core::str::from_utf8(&self.bytes[..self.len]).unwrap_or("")
+        }
+    }
+
+    impl Write for LineBuffer {
+        fn write_str(&mut self, text: &str) -> core::fmt::Result {
+            for byte in text.bytes() {
+                if self.len < self.bytes.len() {
+                    self.bytes[self.len] = byte;
+                    self.len += 1;
+                }
+            }
+            Ok(())
+        }
+    }
+
+}
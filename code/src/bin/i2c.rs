@@ -7,40 +7,143 @@ use defmt_rtt as _;
 
 
 #[app(device = board, peripherals = false, dispatchers = [SWI0_EGU0,
-                                                        SWI1_EGU1])] 
+                                                        SWI1_EGU1])]
 mod app {
     use board::*;
+    use systick_monotonic::*;
+
+    #[monotonic(binds = SysTick, default = true)]
+    type MyMono = Systick<1000>;
+
+    // Emulated sensor on the TWIS
+    const REG_WHO_AM_I: u8 = 0x00;
+    const REG_SECONDS: u8 = 0x01;
+    const REG_CONTROL: u8 = 0x02;
+    const REG_STATUS: u8 = 0x03;
 
     #[local]
     struct LocalResources {
+        seconds: u32,
     }
 
     #[shared]
     struct SharedResources {
+        // Used from two priorities, every transfer takes the lock
+        i2c: I2c<TWIM0>,
+        #[lock_free]
+        display: Ssd1306,
+        #[lock_free]
+        frame: Framebuffer,
+        // Profiles without TWIS leave the emulated sensor out
+        #[lock_free]
+        twis: Option<Twis<TWIS1>>,
     }
 
     #[init]
-    fn init(_ctx: init::Context) 
+    fn init(_ctx: init::Context)
     -> (SharedResources, LocalResources, init::Monotonics) {
-        let my_board = board::init_board().unwrap();
+        let my_board = BoardBuilder::new()
+            .cts(None)
+            .uarte(None)
+            .spim(None)
+            .bmlite(None)
+            .spi(None, None)
+            .nfct(false)
+            .build().unwrap();
         defmt::info!("Board initialized\n----------");
 
+        let mono = Systick::new(_ctx.core.SYST, 64_000_000);
+
+        let mut i2c = my_board.board_i2c.unwrap();
+        let mut display = Ssd1306::new(SSD1306_ADDR, I2C_DATA_BUF);
+        if display.init(&mut i2c).is_err() {
+            defmt::error!("SSD1306 not responding");
+        }
+
+        let mut twis = my_board.board_twis;
+        match twis.as_mut() {
+            Some(twis) => {
+                let map = twis.map_mut();
+                map.set(REG_WHO_AM_I, 0xA5);
+                map.set_writable(REG_CONTROL, true);
+                map.set_on_write(Some(control_written));
+            },
+            None => defmt::warn!("No TWIS on this board, sensor emulation disabled"),
+        }
+
+        let mut frame = Framebuffer::new();
+        frame.text(0, 0, "NRF_RTIC", true);
+        frame.rect(0, 10, DISPLAY_WIDTH as i32, 54, true);
+        frame.circle(100, 37, 20, true);
+        frame.line(4, 60, 60, 14, true);
+        frame.flush(&mut display, &mut i2c).ok();
 
         defmt::info!("Peripherials turned on\n----------");
+        display_counter::spawn_after(1.secs()).ok();
+        bus_probe::spawn_after(5.secs()).ok();
 
-        ( 
+        (
             SharedResources {
+                i2c,
+                display,
+                frame,
+                twis,
             },
             LocalResources  {
+                seconds: 0,
             },
-            init::Monotonics(),
+            init::Monotonics(mono),
         )
     }
 
-    #[task()]
-    fn example(cx: example::Context)  {
+    // Seconds counter, only the pages under the text go over I2C
+    #[task(local = [seconds], shared = [i2c, display, frame, twis])]
+    fn display_counter(cx: display_counter::Context)  {
+        *cx.local.seconds += 1;
+        if let Some(twis) = cx.shared.twis.as_mut() {
+            twis.map_mut().set(REG_SECONDS, *cx.local.seconds as u8);
+        }
+
+        let mut digits = [b'0'; 6];
+        let mut value = *cx.local.seconds;
+        for digit in digits.iter_mut().rev() {
+            *digit = b'0' + (value % 10) as u8;
+            value /= 10;
+        }
+
+        let frame = cx.shared.frame;
+        frame.fill_rect(CHAR_WIDTH * 10, 0, CHAR_WIDTH * 6, LINE_HEIGHT, false);
+        frame.text(CHAR_WIDTH * 10, 0, core::str::from_utf8(&digits).unwrap_or(""), true);
+        // Display stages in `I2C_DATA_BUF`, the proxy copies into its own buffer under the lock
+        let mut bus = I2cProxy::new(cx.shared.i2c, SSD1306_ADDR, I2C_PROXY_BUF, I2C_PROXY_BUF_LEN as usize);
+        frame.flush(cx.shared.display, &mut bus).ok();
+
+        display_counter::spawn_after(1.secs()).ok();
+    }
+
+    // Status mirrors the last control value, bit 7 tells it was taken
+    fn control_written(map: &mut RegisterMap, register: u8, value: u8) {
+        if register == REG_CONTROL {
+            map.set(REG_STATUS, value | 0x80);
+        }
     }
 
-}
+    // Master accessed the emulated sensor
+    #[task(binds = SPIM1_SPIS1_TWIM1_TWIS1_SPI1_TWI1, shared = [twis])]
+    fn twis_interrupt(cx: twis_interrupt::Context)  {
+        if let Some(event) = cx.shared.twis.as_mut().and_then(|twis| twis.handle()) {
+            defmt::info!("TWIS: {}", event);
+        }
+    }
+
+    // Higher priority user of the same bus, squeezes in between display transfers;
+    // scan talks to every address, so it takes the bus itself instead of a proxy
+    #[task(priority = 2, shared = [i2c])]
+    fn bus_probe(mut cx: bus_probe::Context)  {
+        let scan = cx.shared.i2c.lock(i2c_scan);
+        defmt::info!("I2C scan: {=usize} device(s)", scan.len());
 
+        bus_probe::spawn_after(5.secs()).ok();
+    }
 
+}
@@ -11,16 +11,36 @@ use defmt_rtt as _;
                                                         SWI1_EGU1])] 
 mod app {
     use board::{*, UARTE_RX_BUF_DEF, UARTE_RX_BUF_MAXLEN};
-    use systick_monotonic::*;
 
-    #[monotonic(binds = SysTick, default = true)]    
-    type MyMono = Systick<10>;
+    // Keeps counting with the CPU asleep and HFCLK off
+    #[monotonic(binds = RTC1, default = true)]
+    type MyMono = RtcMonotonic<RTC1>;
+
+    // What GPIOTE registrations stand for
+    #[derive(Clone, Copy, PartialEq, Eq)]
+    pub enum GpioEvent {
+        Buttons,
+        UarteCts,
+    }
+
+    // Timeouts armed in `timeouts`
+    #[derive(Clone, Copy, PartialEq, Eq)]
+    pub enum TimeoutKey {
+        UarteRx,
+    }
+
+    // Whole frame has to come in this time after CTS
+    const UARTE_RX_TIMEOUT_MS: u32 = 10_000;
 
     #[local]
     struct LocalResources {
         buttons: Buttons,
-        system_on: bool,
-        nfct: Nfct,
+        // Profiles without NFC antenna leave it out
+        nfct: Option<Nfct>,
+        clocks: BoardClocks,
+        power: Power,
+        wakes: [WakeSource; 2],
+        wake_count: usize,
     }
 
     #[shared]
@@ -28,147 +48,344 @@ mod app {
         #[lock_free]
         leds: Leds,
         #[lock_free]
-        gpiote: Gpiote,
+        gpiote: GpioteManager<GpioEvent>,
         #[lock_free]
         uarte: Uarte<UARTE0>,
         #[lock_free]
-        timers: Timers,
+        i2c: I2c<TWIM0>,
+        #[lock_free]
+        button_pipeline: ButtonPipeline,
+        #[lock_free]
+        led_patterns: LedPatterns,
+        #[lock_free]
+        timeouts: Timeouts<TimeoutKey>,
+        #[lock_free]
+        timeout_tick_handle: Option<timeout_tick::SpawnHandle>,
     }
 
     #[init]
     fn init(_ctx: init::Context) 
     -> (SharedResources, LocalResources, init::Monotonics) {
-        let my_board = board::init_board().unwrap();
+        let my_board = match BoardBuilder::new()
+            // Nothing here needs HFXO, HFINT runs only while peripherals need it
+            .clocks(HfClockSource::Internal, LfClockSource::Crystal)
+            .twis(None)
+            .spim(None)
+            .bmlite(None)
+            .spi(None, None)
+            .build() {
+            Ok(board) => board,
+            // Missing crystals fall back to RC oscillators, this is a broken board,
+            // resetting would only fail the same way again
+            Err(error) => defmt::panic!("Board init failed: {}", error),
+        };
         defmt::info!("Board initialized\n----------");
 
-        let clk = _ctx.core.SYST;
-        let mono = Systick::new(clk, 64_000_000);
+        // Calibrates LFRC only if the crystal failed and LFCLK fell back to RC
+        let mut clocks = my_board.board_clocks;
+        defmt::info!("LFCLK source: {}", clocks.lf_source());
+        clocks.start_calibration_timer(LFRC_CAL_INTERVAL);
+
+        let mono = RtcMonotonic::new(my_board.board_rtc, &clocks);
 
         let leds = my_board.leds;
         let buttons = my_board.buttons;
+        // Long press of button 4 turns System OFF, button 1 or NFC field wakes
+        let nfct = my_board.board_nfct;
+        let wakes = [WakeSource::button(&buttons._1), WakeSource::Nfc];
+        let wake_count = if nfct.is_some() { 2 } else { 1 };
+
+        let mut uarte = my_board.board_uarte.unwrap();
+
+        // Tell why the board restarted, RESETREAS is cleared by now
+        let reset_reason = my_board.board_reset_reason;
+        defmt::info!("Reset reason: {}", reset_reason);
+        reset_reason.write_report(&mut uarte.writer(UARTE_TX_BUF_DEF, UARTE_TX_BUF_MAXLEN)).ok();
+
+        // Show what is wired to the I2C bus
+        let mut i2c = my_board.board_i2c.unwrap();
+        let scan = i2c_scan(&mut i2c);
+        defmt::info!("I2C scan: {=usize} device(s)", scan.len());
+        for address in scan.iter() {
+            defmt::info!("  I2C device at {=u8:#04x}", address);
+        }
 
-        let uarte = my_board.board_uarte;
+        // Buttons share PORT event, CTS falling edge gets own channel
+        let mut gpiote = GpioteManager::new(my_board.board_gpiote);
+        for button in [&buttons._1, &buttons._2, &buttons._3, &buttons._4] {
+            gpiote.port(&button.inner, PortEventSense::Low, GpioEvent::Buttons).unwrap();
+        }
+        // Without CTS line frames are never announced, UARTE stays idle
+        match my_board.board_cts.as_ref() {
+            Some(cts) => {
+                gpiote.channel(cts, EventPolarity::HiToLo, GpioEvent::UarteCts).unwrap();
+            },
+            None => defmt::warn!("No UARTE CTS pin, shell disabled"),
+        }
 
         defmt::info!("Peripherials turned on\n----------");
 
-        let system_on = true;
-        system_on::spawn_after(1.secs()).unwrap();
+        // Heartbeat on LED4 to indicate that uC is working
+        let mut led_patterns = LedPatterns::new();
+        led_patterns.play(4, Pattern::Heartbeat, 0);
+        led_pattern::spawn(None).ok();
 
         ( 
             SharedResources {
-                gpiote: my_board.board_gpiote,
-                leds: leds,
-                uarte: uarte,
-                timers: my_board.board_timers,
+                gpiote,
+                leds,
+                uarte,
+                i2c,
+                button_pipeline: ButtonPipeline::new(20, GestureConfig::default()),
+                led_patterns,
+                timeouts: Timeouts::new(),
+                timeout_tick_handle: None,
             },
             LocalResources  {
-                system_on,
-                buttons: buttons,
-                nfct: my_board.board_nfct,
+                buttons,
+                nfct,
+                clocks,
+                power: my_board.board_power,
+                wakes,
+                wake_count,
                 //uarte: my_board.uarte_board,
             },
             init::Monotonics(mono),
         )
     }
 
-    // Task to indicate that uC is working
-    #[task(shared = [leds])]
-    fn system_diode(cx: system_diode::Context)  {
-        cx.shared.leds._4.toggle();
-        system_on::spawn().unwrap();
-    }
-    #[task(local = [system_on])]
-    fn system_on(cx: system_on::Context)    {
-        defmt::trace!("system_on_function");
-        if *cx.local.system_on  {
-            system_diode::spawn_after(500.millis()).ok();
+    // Sleep between events, RTC keeps the monotonic going
+    #[idle]
+    fn idle(_cx: idle::Context) -> ! {
+        loop {
+            system_on_idle();
         }
     }
 
+    // Plays LED patterns, spawn it after changing a pattern to apply it
+    #[task(capacity = 4, shared = [leds, led_patterns])]
+    fn led_pattern(cx: led_pattern::Context, fired: Option<u32>)  {
+        cx.shared.led_patterns.run(now_ms(), cx.shared.leds, fired, spawn_led_pattern);
+    }
+
+    fn spawn_led_pattern(left: u32, deadline: u32) -> bool {
+        led_pattern::spawn_after((left as u64).millis(), Some(deadline)).is_ok()
+    }
+
 
 
     // Interrupt handler for GPIOTE
     #[task(binds = GPIOTE, 
         shared = [gpiote,
+        button_pipeline,
         ])]
     fn GPIOTE_interrupt(cx: GPIOTE_interrupt::Context)  {
-        // If button was pushed
-        if cx.shared.gpiote.port().is_event_triggered() {
-            debounce::spawn().unwrap();
-        }
-        cx.shared.gpiote.reset_events();
-
-        if cx.shared.gpiote.channel0().is_event_triggered() {
-            uarte_receive::spawn().unwrap();
+        for event in cx.shared.gpiote.dispatch() {
+            match event {
+                // Button pushed or released, bursts are coalesced until pins are quiet
+                GpioEvent::Buttons => cx.shared.button_pipeline.edge(spawn_debounce),
+                GpioEvent::UarteCts => {
+                    uarte_receive_start::spawn().ok();
+                },
+            }
         }
-        
     }
 
-    // Task for GPIOTE service
+    // Task for GPIOTE service, samples buttons once pins settled
     #[task(local = [buttons,
         ],
-        shared = [leds,
-        uarte,
+        shared = [button_pipeline,
+        gpiote,
         ])]
     fn debounce(cx: debounce::Context)  {
-        // Map resources
         let buttons = cx.local.buttons;
+        if buttons.debounce(cx.shared.button_pipeline, cx.shared.gpiote, now_ms(), spawn_debounce) {
+            button_events::spawn().ok();
+        }
+    }
+
+    // Resolve click and long press timeouts
+    #[task(capacity = 4, shared = [button_pipeline])]
+    fn gesture_tick(cx: gesture_tick::Context, deadline: u32)  {
+        if cx.shared.button_pipeline.poll(now_ms(), deadline) {
+            button_events::spawn().ok();
+        }
+    }
+
+    fn spawn_debounce(settle_ms: u32) -> bool {
+        debounce::spawn_after((settle_ms as u64).millis()).is_ok()
+    }
+
+    fn spawn_gesture_tick(left: u32, deadline: u32) -> bool {
+        gesture_tick::spawn_after((left as u64).millis(), deadline).is_ok()
+    }
+
+    // Handle button gestures and plan next timeout
+    #[task(local = [power,
+        wakes,
+        wake_count,
+        ],
+        shared = [button_pipeline,
+        leds,
+        led_patterns,
+        uarte,
+        i2c,
+        ])]
+    fn button_events(cx: button_events::Context)  {
+        let pipeline = cx.shared.button_pipeline;
         let leds = cx.shared.leds;
-        // Add condition for each port event
-        if buttons._1.is_pushed() { leds._1.toggle();
-            defmt::info!("button1 pushed");
-            cx.shared.uarte.transmit(UARTE_TX_BUF_DEF, UARTE_TX_BUF_MAXLEN);
-            //cx.shared.uarte.transmit(0x2000_0000, 4).unwrap();
-        } else if buttons._2.is_pushed() { leds._2.toggle();
-            defmt::info!("button2 pushed");
-        } else if buttons._3.is_pushed() { leds._3.toggle();
-            defmt::info!("button3 pushed");
-        } else if buttons._4.is_pushed() { leds._3.toggle();
-            defmt::info!("button4 pushed");
+
+        while let Some(event) = pipeline.pop() {
+            defmt::info!("button event: {}", event);
+            match event {
+                ButtonEvent::Click(1) => { leds._1.toggle();
+                    cx.shared.uarte.transmit(UARTE_TX_BUF_DEF, UARTE_TX_BUF_MAXLEN).ok();
+                },
+                ButtonEvent::Click(2) => leds._2.toggle(),
+                ButtonEvent::Click(3) => leds._3.toggle(),
+                ButtonEvent::DoubleClick(n) => {
+                    cx.shared.led_patterns.play(n, Pattern::Blink { times: 3, on_ms: 100, off_ms: 100 }, now_ms());
+                    led_pattern::spawn(None).ok();
+                },
+                ButtonEvent::LongPress(4, _) => {
+                    defmt::info!("System OFF");
+                    leds._1.off();
+                    leds._2.off();
+                    leds._3.off();
+                    leds._4.off();
+                    let wakes = &cx.local.wakes[..*cx.local.wake_count];
+                    cx.local.power.system_off(wakes, &mut [cx.shared.uarte, cx.shared.i2c]);
+                },
+                ButtonEvent::LongPress(..) => {
+                    leds._1.off();
+                    leds._2.off();
+                    leds._3.off();
+                },
+                _ => {},
+            }
+        }
+
+        pipeline.plan_tick(now_ms(), spawn_gesture_tick);
+    }
+
+    // Monotonic time for gesture engine
+    fn now_ms() -> u32 {
+        monotonics::now().duration_since_epoch().to_millis() as u32
+    }
+
+
+
+
+    // Expire due timeouts and plan the next check
+    #[task(shared = [timeouts, timeout_tick_handle, uarte])]
+    fn timeout_tick(cx: timeout_tick::Context)  {
+        let timeouts = cx.shared.timeouts;
+        while let Some(key) = timeouts.pop_expired(now_ms()) {
+            match key {
+                TimeoutKey::UarteRx => {
+                    let received = cx.shared.uarte.end_receive();
+                    uarte_command::spawn(received).ok();
+                },
+            }
+        }
+        *cx.shared.timeout_tick_handle = None;
+        schedule_timeouts(timeouts, cx.shared.timeout_tick_handle);
+    }
+
+    // Only one tick is needed, always for the closest deadline
+    fn schedule_timeouts(timeouts: &Timeouts<TimeoutKey>,
+        handle: &mut Option<timeout_tick::SpawnHandle>) {
+        if let Some(handle) = handle.take() {
+            handle.cancel().ok();
+        }
+        if let Some(left) = timeouts.next_deadline(now_ms()) {
+            *handle = timeout_tick::spawn_after((left as u64).millis()).ok();
         }
     }
 
+    // CTS announced a frame, receive it in the background
+    #[task(shared = [uarte, timeouts, timeout_tick_handle, led_patterns])]
+    fn uarte_receive_start(cx: uarte_receive_start::Context)    {
+        let timeouts = cx.shared.timeouts;
+        if timeouts.is_armed(TimeoutKey::UarteRx) {
+            return;
+        }
 
+        if let Err(error) = cx.shared.uarte.begin_receive(UARTE_RX_BUF_DEF, UARTE_RX_BUF_MAXLEN) {
+            defmt::error!("UARTE receive failed: {}", defmt::Debug2Format(&error));
+            cx.shared.led_patterns.play(3, Pattern::ErrorCode(2), now_ms());
+            led_pattern::spawn(None).ok();
+            return;
+        }
+        timeouts.arm(TimeoutKey::UarteRx, now_ms(), UARTE_RX_TIMEOUT_MS).ok();
+        schedule_timeouts(timeouts, cx.shared.timeout_tick_handle);
+    }
 
+    // Interrupt handler for Uarte, buffer full ends the reception early
+    #[task(binds = UARTE0_UART0, shared = [uarte, timeouts, timeout_tick_handle])]
+    fn uarte_interrupt(cx: uarte_interrupt::Context)    {
+        let uarte = cx.shared.uarte;
+        // CTS is handled through GPIOTE
+        if uarte.is_cts() {
+            uarte.clear_cts_event();
+        }
+        if uarte.is_rx_done() && cx.shared.timeouts.cancel(TimeoutKey::UarteRx) {
+            let received = uarte.end_receive();
+            uarte_command::spawn(received).ok();
+            schedule_timeouts(cx.shared.timeouts, cx.shared.timeout_tick_handle);
+        }
+    }
 
-    // Interrupt handler for Uarte reception
-    //#[task(binds = UARTE0_UART0, shared = [uarte, timers])]
-    #[task(shared = [uarte, timers, gpiote])]
-    fn uarte_receive(cx: uarte_receive::Context)    {
-        defmt::debug!("Entered cts interrupt");
-        cx.shared.uarte.receive(UARTE_RX_BUF_DEF, UARTE_RX_BUF_MAXLEN, 
-            &mut cx.shared.timers.tim0
-        ).unwrap();
-        //cx.shared.uarte.clear_cts_event();
-        cx.shared.gpiote.reset_events();
-        /*/
-        if cx.shared.uarte.is_cts() {
-            defmt::debug!("Entered cts interrupt");
-            cx.shared.uarte.receive(UARTE_RX_BUF_DEF, UARTE_RX_BUF_MAXLEN, 
-                &mut cx.shared.timers.tim0
-            ).unwrap();
-            cx.shared.uarte.clear_cts_event();
-            defmt::debug!("Left interrupt");
+    // Received bytes are a shell command, ended by new line or the buffer end
+    #[task(shared = [uarte])]
+    fn uarte_command(cx: uarte_command::Context, received: usize)    {
+        let received = received.min(UARTE_RX_BUF_MAXLEN as usize);
+        let rx = unsafe { core::slice::from_raw_parts(UARTE_RX_BUF_DEF as *const u8, received) };
+        let end = rx.iter().position(|byte| *byte == b'\r' || *byte == b'\n').unwrap_or(rx.len());
+        match &rx[..end] {
+            b"" => {},
+            b"scan" => { i2c_scan_command::spawn().ok(); },
+            _ => {
+                cx.shared.uarte.write_bytes(UARTE_TX_BUF_DEF, UARTE_TX_BUF_MAXLEN,
+                    b"unknown command\r\n").ok();
+            },
         }
-        */
+    }
+
+    // `scan` shell command, prints ACKing I2C addresses
+    #[task(shared = [i2c, uarte])]
+    fn i2c_scan_command(cx: i2c_scan_command::Context)  {
+        let scan = i2c_scan(cx.shared.i2c);
+        let mut writer = cx.shared.uarte.writer(UARTE_TX_BUF_DEF, UARTE_TX_BUF_MAXLEN);
+        scan.write_report(&mut writer).ok();
     }
 
     // Transmit UARTE frame
     #[task(shared = [uarte])]
     fn uarte_transmit(cx: uarte_transmit::Context)    {
-        cx.shared.uarte.transmit(UARTE_TX_BUF_DEF, UARTE_TX_BUF_MAXLEN);
+        cx.shared.uarte.transmit(UARTE_TX_BUF_DEF, UARTE_TX_BUF_MAXLEN).ok();
     }
 
     // Interrupt handler for NFCT
     #[task(binds = NFCT, local = [nfct])]
     fn nfc(cx: nfc::Context)   {
-        let nfc = cx.local.nfct;
+        let Some(nfc) = cx.local.nfct else {
+            return;
+        };
         if nfc.field_detected()  {
             defmt::info!("Field detect interrupt entered:");
         }
         nfc.reset_events();
     }
 
+    // LFRC calibration, only fires when LFCLK fell back to RC
+    #[task(binds = POWER_CLOCK, local = [clocks])]
+    fn power_clock(cx: power_clock::Context)   {
+        if let Some(event) = cx.local.clocks.on_interrupt() {
+            defmt::debug!("clock: {}", event);
+        }
+    }
+
 
 }
 
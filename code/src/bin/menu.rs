@@ -0,0 +1,254 @@
+#![no_std]
+#![no_main]
+
+use rtic::app;
+use panic_probe as _;
+use defmt_rtt as _;
+
+
+#[app(device = board, peripherals = false, dispatchers = [SWI0_EGU0,
+                                                        SWI1_EGU1])]
+mod app {
+    use board::*;
+    use systick_monotonic::*;
+
+    #[monotonic(binds = SysTick, default = true)]
+    type MyMono = Systick<1000>;
+
+    // Ids of menu actions, status screens and values
+    const TOGGLE_LED1: u8 = 0;
+    const TOGGLE_LED2: u8 = 1;
+    const TOGGLE_LED3: u8 = 2;
+    const LEDS_OFF: u8 = 3;
+    const UPTIME: u8 = 0;
+    const CONTRAST: u8 = 0;
+    const INVERTED: u8 = 1;
+    const DOUBLE_CLICK: u8 = 2;
+    const LONG_PRESS: u8 = 3;
+
+    static LEDS_MENU: Menu = Menu {
+        title: "LEDs",
+        items: &[
+            MenuItem::Action("Toggle LED1", TOGGLE_LED1),
+            MenuItem::Action("Toggle LED2", TOGGLE_LED2),
+            MenuItem::Action("Toggle LED3", TOGGLE_LED3),
+            MenuItem::Action("All off", LEDS_OFF),
+        ],
+    };
+
+    static DISPLAY_MENU: Menu = Menu {
+        title: "Display",
+        items: &[
+            MenuItem::Value("Contrast", CONTRAST, ValueSpec { min: 0, max: 255, step: 16, initial: 0xCF }),
+            MenuItem::Value("Inverted", INVERTED, ValueSpec { min: 0, max: 1, step: 1, initial: 0 }),
+        ],
+    };
+
+    static BUTTONS_MENU: Menu = Menu {
+        title: "Buttons",
+        items: &[
+            MenuItem::Value("Double click ms", DOUBLE_CLICK, ValueSpec { min: 100, max: 1000, step: 50, initial: 300 }),
+            MenuItem::Value("Long press ms", LONG_PRESS, ValueSpec { min: 300, max: 3000, step: 100, initial: 800 }),
+        ],
+    };
+
+    static MAIN_MENU: Menu = Menu {
+        title: "NRF_RTIC",
+        items: &[
+            MenuItem::Submenu("LEDs", &LEDS_MENU),
+            MenuItem::Submenu("Display", &DISPLAY_MENU),
+            MenuItem::Submenu("Buttons", &BUTTONS_MENU),
+            MenuItem::Status("Uptime", UPTIME),
+        ],
+    };
+
+    #[local]
+    struct LocalResources {
+        buttons: Buttons,
+    }
+
+    #[shared]
+    struct SharedResources {
+        #[lock_free]
+        leds: Leds,
+        #[lock_free]
+        gpiote: GpioteManager<()>,
+        #[lock_free]
+        button_pipeline: ButtonPipeline,
+        #[lock_free]
+        menu: MenuNav,
+        #[lock_free]
+        i2c: I2c<TWIM0>,
+        #[lock_free]
+        display: Ssd1306,
+        #[lock_free]
+        frame: Framebuffer,
+    }
+
+    #[init]
+    fn init(_ctx: init::Context)
+    -> (SharedResources, LocalResources, init::Monotonics) {
+        let my_board = BoardBuilder::new()
+            .cts(None)
+            .uarte(None)
+            .twis(None)
+            .spim(None)
+            .bmlite(None)
+            .spi(None, None)
+            .nfct(false)
+            .build().unwrap();
+        defmt::info!("Board initialized\n----------");
+
+        let mono = Systick::new(_ctx.core.SYST, 64_000_000);
+
+        let buttons = my_board.buttons;
+        let mut gpiote = GpioteManager::new(my_board.board_gpiote);
+        for button in [&buttons._1, &buttons._2, &buttons._3, &buttons._4] {
+            gpiote.port(&button.inner, PortEventSense::Low, ()).unwrap();
+        }
+
+        let mut i2c = my_board.board_i2c.unwrap();
+        let mut display = Ssd1306::new(SSD1306_ADDR, I2C_DATA_BUF);
+        if display.init(&mut i2c).is_err() {
+            defmt::error!("SSD1306 not responding");
+        }
+
+        defmt::info!("Peripherials turned on\n----------");
+        display_refresh::spawn().ok();
+
+        (
+            SharedResources {
+                leds: my_board.leds,
+                gpiote,
+                button_pipeline: ButtonPipeline::new(20, GestureConfig::default()),
+                menu: MenuNav::new(&MAIN_MENU),
+                i2c,
+                display,
+                frame: Framebuffer::new(),
+            },
+            LocalResources  {
+                buttons,
+            },
+            init::Monotonics(mono),
+        )
+    }
+
+    // Buttons pushed or released, sample them once pins settle
+    #[task(binds = GPIOTE, shared = [gpiote, button_pipeline])]
+    fn GPIOTE_interrupt(cx: GPIOTE_interrupt::Context)  {
+        for () in cx.shared.gpiote.dispatch() {
+            cx.shared.button_pipeline.edge(spawn_debounce);
+        }
+    }
+
+    #[task(local = [buttons], shared = [button_pipeline, gpiote])]
+    fn debounce(cx: debounce::Context)  {
+        let buttons = cx.local.buttons;
+        if buttons.debounce(cx.shared.button_pipeline, cx.shared.gpiote, now_ms(), spawn_debounce) {
+            button_events::spawn().ok();
+        }
+    }
+
+    #[task(capacity = 4, shared = [button_pipeline])]
+    fn gesture_tick(cx: gesture_tick::Context, deadline: u32)  {
+        if cx.shared.button_pipeline.poll(now_ms(), deadline) {
+            button_events::spawn().ok();
+        }
+    }
+
+    fn spawn_debounce(settle_ms: u32) -> bool {
+        debounce::spawn_after((settle_ms as u64).millis()).is_ok()
+    }
+
+    fn spawn_gesture_tick(left: u32, deadline: u32) -> bool {
+        gesture_tick::spawn_after((left as u64).millis(), deadline).is_ok()
+    }
+
+    // Feed clicks to the menu and act on what it reports
+    #[task(shared = [button_pipeline,
+        menu,
+        leds,
+        i2c,
+        display,
+        ])]
+    fn button_events(cx: button_events::Context)  {
+        let pipeline = cx.shared.button_pipeline;
+        let menu = cx.shared.menu;
+        let leds = cx.shared.leds;
+        let mut redraw = false;
+
+        while let Some(event) = pipeline.pop() {
+            let Some(nav) = Nav::from_button(event) else {
+                continue;
+            };
+            let Some(menu_event) = menu.handle(nav) else {
+                continue;
+            };
+            defmt::info!("menu event: {}", menu_event);
+            redraw = true;
+
+            match menu_event {
+                MenuEvent::Action(TOGGLE_LED1) => leds._1.toggle(),
+                MenuEvent::Action(TOGGLE_LED2) => leds._2.toggle(),
+                MenuEvent::Action(TOGGLE_LED3) => leds._3.toggle(),
+                MenuEvent::Action(LEDS_OFF) => {
+                    leds._1.off();
+                    leds._2.off();
+                    leds._3.off();
+                },
+                MenuEvent::ValueChanged(CONTRAST, value) => {
+                    cx.shared.display.set_contrast(cx.shared.i2c, value as u8).ok();
+                },
+                MenuEvent::ValueChanged(INVERTED, value) => {
+                    cx.shared.display.set_inverted(cx.shared.i2c, value != 0).ok();
+                },
+                MenuEvent::ValueChanged(DOUBLE_CLICK | LONG_PRESS, _) => {
+                    pipeline.set_config(GestureConfig {
+                        double_click_ms: menu.value(DOUBLE_CLICK) as u32,
+                        long_press_ms: menu.value(LONG_PRESS) as u32,
+                    });
+                },
+                _ => {},
+            }
+        }
+
+        if redraw {
+            display_refresh::spawn().ok();
+        }
+
+        pipeline.plan_tick(now_ms(), spawn_gesture_tick);
+    }
+
+    // Draw current screen, status screens refresh themselves every second
+    #[task(capacity = 2,
+        local = [timeout: Option<display_refresh::SpawnHandle> = None],
+        shared = [menu, frame, display, i2c])]
+    fn display_refresh(cx: display_refresh::Context)  {
+        if let Some(handle) = cx.local.timeout.take() {
+            handle.cancel().ok();
+        }
+
+        let menu = cx.shared.menu;
+        let frame = cx.shared.frame;
+        menu.render(frame);
+
+        if menu.status() == Some(UPTIME) {
+            let mut digits = [b'0'; 6];
+            let mut value = now_ms() / 1000;
+            for digit in digits.iter_mut().rev() {
+                *digit = b'0' + (value % 10) as u8;
+                value /= 10;
+            }
+            frame.text(0, LINE_HEIGHT * 2, "Seconds:", true);
+            frame.text(CHAR_WIDTH * 9, LINE_HEIGHT * 2, core::str::from_utf8(&digits).unwrap_or(""), true);
+            *cx.local.timeout = display_refresh::spawn_after(1.secs()).ok();
+        }
+
+        frame.flush(cx.shared.display, cx.shared.i2c).ok();
+    }
+
+    fn now_ms() -> u32 {
+        monotonics::now().duration_since_epoch().to_millis() as u32
+    }
+
+}
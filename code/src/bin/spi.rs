@@ -0,0 +1,81 @@
+#![no_std]
+#![no_main]
+
+use rtic::app;
+use panic_probe as _;
+use defmt_rtt as _;
+
+
+#[app(device = board, peripherals = false, dispatchers = [SWI0_EGU0,
+                                                        SWI1_EGU1])]
+mod app {
+    use board::*;
+    use systick_monotonic::*;
+
+    #[monotonic(binds = SysTick, default = true)]
+    type MyMono = Systick<1000>;
+
+    // JEDEC "read identification" of SPI NOR flash
+    const FLASH_READ_ID: u8 = 0x9F;
+
+    #[local]
+    struct LocalResources {
+        spi: Spim<SPIM3>,
+        flash: SpiDevice,
+    }
+
+    #[shared]
+    struct SharedResources {
+    }
+
+    #[init]
+    fn init(_ctx: init::Context)
+    -> (SharedResources, LocalResources, init::Monotonics) {
+        let my_board = BoardBuilder::bare()
+            .spi(Some(SpimSetup {
+                sck: pin(0, 28),
+                mosi: Some(pin(0, 29)),
+                miso: Some(pin(0, 30)),
+                frequency: Frequency::M8,
+                mode: MODE_0,
+                orc: 0xFF,
+            }), Some([pin(0, 31), pin(0, 4)]))
+            .build().unwrap();
+        defmt::info!("Board initialized\n----------");
+
+        let mono = Systick::new(_ctx.core.SYST, 64_000_000);
+
+        let flash = SpiDevice::new(my_board.board_spi_cs.unwrap()._1,
+            SpiConfig { mode: MODE_0, frequency: Frequency::M8 },
+            SPIM3_TX_BUF_DEF, SPIM3_RX_BUF_DEF, SPIM3_BUF_LEN);
+
+        defmt::info!("Peripherials turned on\n----------");
+        flash_id::spawn().ok();
+
+        (
+            SharedResources {
+            },
+            LocalResources  {
+                spi: my_board.board_spi.unwrap(),
+                flash,
+            },
+            init::Monotonics(mono),
+        )
+    }
+
+    // Command and answer under one chip select
+    #[task(local = [spi, flash])]
+    fn flash_id(cx: flash_id::Context)  {
+        let mut id = [0u8; 3];
+        let result = cx.local.flash.on(cx.local.spi).exec(&mut [
+            Operation::Write(&[FLASH_READ_ID]),
+            Operation::Transfer(&mut id),
+        ]);
+
+        match result {
+            Ok(()) => defmt::info!("Flash JEDEC id: {=[u8]:#04x}", id),
+            Err(error) => defmt::error!("Flash read failed: {}", error),
+        }
+    }
+
+}